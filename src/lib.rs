@@ -2,11 +2,12 @@
 //!
 //! # Utilities
 //!
-//! - [PrettyConsoleLayer](crate::sub::PrettyConsoleLayer): a custom `tracing-subscriber` layer that pretty prints to `stdout`
+//! - [PrettyConsoleLayer](crate::sub::PrettyConsoleLayer): a custom `tracing-subscriber` layer that pretty prints to `stderr` (or `stdout`)
 //!
 //! # Features
 //!
 //! - **subscriber**: activates utilities for `tracing-subscriber`
+//! - **tokio**: shows the IDs of the `tokio` tasks (see [PrettyConsoleLayer::show_task_id](crate::sub::PrettyConsoleLayer::show_task_id))
 
 #[cfg(feature = "subscriber")]
 pub mod sub;
@@ -2,7 +2,9 @@
 //!
 //! This module provides utilities for subscribers
 
-use std::{collections::HashMap, time::Instant};
+use std::{borrow::Cow, time::Instant};
+
+use indexmap::IndexMap;
 
 use tracing_subscriber::registry::SpanRef;
 
@@ -54,52 +56,245 @@ pub trait SpanExtension {
     }
 }
 
+/// Recorded fields (span attributes or event fields)
+///
+/// A field recorded more than once keeps all its values, which are rendered as a list (`[v1, v2]`).
+/// Fields are iterated in the order they were first recorded.
+#[derive(Debug, Default, Clone)]
+pub struct Fields {
+    /// Values, by field name
+    values: IndexMap<&'static str, Vec<String>>,
+    /// Numeric values (last value), by field name
+    numbers: IndexMap<&'static str, f64>,
+}
+
+impl Fields {
+    /// Records a field value
+    pub fn insert(&mut self, name: &'static str, value: String) {
+        self.values.entry(name).or_default().push(value);
+    }
+
+    /// Returns a field value
+    pub fn get(&self, name: &str) -> Option<Cow<'_, str>> {
+        self.values.get(name).map(|values| Self::render(values))
+    }
+
+    /// Returns the numeric value of a field, if it has a single value recorded as a number
+    pub fn number(&self, name: &str) -> Option<f64> {
+        match self.values.get(name)?.len() {
+            1 => self.numbers.get(name).copied(),
+            _ => None,
+        }
+    }
+
+    /// Merges fields, replacing the values of the existing fields (which keep their position)
+    pub fn merge(&mut self, other: Fields) {
+        self.values.extend(other.values);
+        self.numbers.extend(other.numbers);
+    }
+
+    /// Removes a field
+    fn remove(&mut self, name: &str) {
+        self.values.shift_remove(name);
+        self.numbers.shift_remove(name);
+    }
+
+    /// Replaces the values of the given fields with `***`
+    fn redact(&mut self, names: &[&str]) {
+        for name in names {
+            if let Some(values) = self.values.get_mut(*name) {
+                *values = vec!["***".to_string()];
+                self.numbers.shift_remove(*name);
+            }
+        }
+    }
+
+    /// Records a numeric field value
+    fn insert_number(&mut self, name: &'static str, value: impl ToString, number: f64) {
+        self.insert(name, value.to_string());
+        self.numbers.insert(name, number);
+    }
+
+    /// Iterates over the fields
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, Cow<'_, str>)> {
+        self.values
+            .iter()
+            .map(|(name, values)| (*name, Self::render(values)))
+    }
+
+    /// Returns the number of fields
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Checks if there are no fields
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Renders the values of a field
+    fn render(values: &[String]) -> Cow<'_, str> {
+        match values {
+            [value] => Cow::Borrowed(value),
+            _ => Cow::Owned(format!("[{}]", values.join(", "))),
+        }
+    }
+}
+
+impl tracing::field::Visit for Fields {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        let value = format!("{value:?}");
+        self.insert(field.name(), value);
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        self.insert(field.name(), value.to_string());
+    }
+
+    fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
+        self.insert(field.name(), value.to_string());
+    }
+
+    fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
+        self.insert_number(field.name(), value, value);
+    }
+
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
+        self.insert_number(field.name(), value, value as f64);
+    }
+
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        self.insert_number(field.name(), value, value as f64);
+    }
+}
+
 /// A span extension to record the span attributes
 #[derive(Debug, Default)]
 pub struct SpanExtAttrs {
     /// Attributes values
-    attrs: HashMap<&'static str, String>,
+    attrs: Fields,
 }
 
 impl SpanExtension for SpanExtAttrs {}
 
 impl tracing::field::Visit for SpanExtAttrs {
     fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
-        let value = format!("{value:?}");
-        self.attrs.insert(field.name(), value);
+        self.attrs.record_debug(field, value);
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        self.attrs.record_str(field, value);
+    }
+
+    fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
+        self.attrs.record_bool(field, value);
+    }
+
+    fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
+        self.attrs.record_f64(field, value);
+    }
+
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
+        self.attrs.record_i64(field, value);
+    }
+
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        self.attrs.record_u64(field, value);
     }
 }
 
 /// A span extensison to record timing info
 #[derive(Debug)]
 pub struct SpanExtTiming {
-    /// Instant when the span was entered
-    pub entered: Instant,
+    /// Instant when the span was created
+    pub created: Instant,
 }
 
 impl Default for SpanExtTiming {
     fn default() -> Self {
         Self {
-            entered: Instant::now(),
+            created: Instant::now(),
         }
     }
 }
 
 impl SpanExtension for SpanExtTiming {}
 
+/// Default name of the event message field
+pub const MESSAGE_FIELD_DEFAULT: &str = "message";
+
+/// Prefix of the lazy span attributes (eg `lazy.body`)
+///
+/// Lazy attributes are only formatted if the span is displayed, and are shown without the prefix.
+pub const LAZY_FIELD_PREFIX: &str = "lazy.";
+
+/// A value formatted by a closure, only when it is recorded
+///
+/// Combined with a lazy attribute (see [LAZY_FIELD_PREFIX]), the closure is not called for spans
+/// which are not displayed.
+///
+/// The closure is called synchronously, on the thread creating the span, and is not stored, so it
+/// does not need to be `Send` or `Sync`.
+///
+/// ```
+/// use tracing_ext::sub::Lazy;
+///
+/// let span = tracing::info_span!("request", lazy.body = ?Lazy(|| "expensive".repeat(10)));
+/// ```
+pub struct Lazy<F>(pub F);
+
+impl<F> std::fmt::Debug for Lazy<F>
+where
+    F: Fn() -> String,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&(self.0)())
+    }
+}
+
 /// A visitor for events
 ///
 /// The visitor saves the event data
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct EventVisitor {
     /// Fields
-    fields: HashMap<&'static str, String>,
+    fields: Fields,
+    /// Name of the field holding the message
+    message_field: &'static str,
+}
+
+impl Default for EventVisitor {
+    fn default() -> Self {
+        Self {
+            fields: Fields::default(),
+            message_field: MESSAGE_FIELD_DEFAULT,
+        }
+    }
 }
 
 impl tracing::field::Visit for EventVisitor {
     fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
-        let value_str = format!("{value:?}");
-        self.fields.insert(field.name(), value_str);
+        self.fields.record_debug(field, value);
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        self.fields.record_str(field, value);
+    }
+
+    fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
+        self.fields.record_bool(field, value);
+    }
+
+    fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
+        self.fields.record_f64(field, value);
+    }
+
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
+        self.fields.record_i64(field, value);
+    }
+
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        self.fields.record_u64(field, value);
     }
 }
 
@@ -113,26 +308,33 @@ impl EventVisitor {
         f_visitor
     }
 
+    /// Records an event fields, with a custom message field (eg `msg`)
+    pub fn record_event_with_message_field(
+        event: &tracing::Event,
+        message_field: &'static str,
+    ) -> Self {
+        let mut f_visitor = EventVisitor {
+            message_field,
+            ..Default::default()
+        };
+        event.record(&mut f_visitor);
+        f_visitor
+    }
+
     /// Returns the event message
+    ///
+    /// If the event has no message field, an empty string is returned
     pub fn message(&self) -> &str {
-        match self.fields.get("message") {
-            Some(s) => s,
-            None => {
-                panic!("Event message not found")
-            }
+        match self.fields.values.get(self.message_field) {
+            Some(values) => values.last().map(|s| s.as_str()).unwrap_or(""),
+            None => "",
         }
     }
 
     /// Returns the event fields (exc. message)
-    pub fn meta_fields(&self) -> HashMap<&'static str, &str> {
-        self.fields
-            .iter()
-            .filter_map(|(k, v)| {
-                if *k == "message" {
-                    return None;
-                }
-                Some((*k, v.as_str()))
-            })
-            .collect()
+    pub fn meta_fields(&self) -> Fields {
+        let mut fields = self.fields.clone();
+        fields.remove(self.message_field);
+        fields
     }
 }
@@ -1,144 +1,2604 @@
 //! A pretty tracing layer for console printing
 
-use std::{collections::HashMap, io::Write, time::Instant};
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
 
-use colored::Colorize;
+use colored::{Color, ColoredString, Colorize};
 use time::macros::format_description;
 use tracing::Level;
-use tracing_subscriber::registry::SpanRef;
+use tracing_subscriber::{
+    fmt::{writer::BoxMakeWriter, MakeWriter},
+    registry::SpanRef,
+};
 
-use super::{EventVisitor, SpanExtension};
+use super::{EventVisitor, Fields, SpanExtension, LAZY_FIELD_PREFIX, MESSAGE_FIELD_DEFAULT};
+
+#[cfg(feature = "serde")]
+mod chrome;
+#[cfg(windows)]
+mod console;
+#[cfg(feature = "serde")]
+mod json;
+mod logfmt;
+#[cfg(feature = "serde")]
+mod replay;
+mod syslog;
+mod tsv;
+#[cfg(feature = "serde")]
+mod yaml;
+
+#[cfg(feature = "serde")]
+pub use replay::{CapturedEvent, CapturedSpan};
+
+/// Width of the level labels (the longest level, `ERROR`), for the messages to be aligned
+const LEVEL_LABEL_WIDTH: usize = 5;
 
 /// Default time format
 const TIME_FORMAT_DEFAULT: &[time::format_description::FormatItem<'static>] =
     format_description!("[hour]:[minute]:[second].[subsecond digits:6]");
 
-/// A tracing layer with pretty print to the console
-///
-/// ```
-///  use tracing_ext::sub::PrettyConsoleLayer;
-///
-///  let pretty_layer = PrettyConsoleLayer::default()
-///     .wrapped(true)
-///     .oneline(false)
-///     .events_only(true)
-///     .show_time(true)
-///     .show_target(true)
-///     .show_file_info(true)
-///     .show_span_info(true)
-///     .indent(6);
-/// ```
-#[derive(Debug, Default)]
-pub struct PrettyConsoleLayer {
-    /// Format
-    format: PrettyFormatOptions,
-}
+/// Placeholder of a timestamp which cannot be formatted with the time format
+const INVALID_TIME: &str = "<invalid time>";
+
+/// A tracing layer with pretty print to the console
+///
+/// ```
+///  use tracing_ext::sub::PrettyConsoleLayer;
+///
+///  let pretty_layer = PrettyConsoleLayer::default()
+///     .wrapped(true)
+///     .oneline(false)
+///     .events_only(true)
+///     .show_time(true)
+///     .show_target(true)
+///     .show_file_info(true)
+///     .show_span_info(true)
+///     .indent(6);
+/// ```
+///
+/// # Span status
+///
+/// If a span has an `otel.status_code` or `_status` attribute, its exit line is colored according to
+/// the attribute value (case insensitive): green for `ok`, red for `error`.
+///
+/// ```
+/// let span = tracing::info_span!("request", otel.status_code = "ok");
+/// ```
+///
+/// # Lazy attributes
+///
+/// Attributes prefixed with [LAZY_FIELD_PREFIX] (eg `lazy.body`) are only formatted if the span is
+/// displayed (i.e. not skipped by sampling, not in the events only mode, and with the pretty format).
+/// Combine them with [Lazy](super::Lazy) to also defer the computation of the value.
+///
+/// ```
+/// use tracing_ext::sub::Lazy;
+///
+/// let span = tracing::info_span!("request", lazy.body = ?Lazy(|| "expensive".repeat(10)));
+/// ```
+#[derive(Debug)]
+pub struct PrettyConsoleLayer {
+    /// Format
+    format: PrettyFormatOptions,
+    /// Sequence number of the last span or event
+    seq: AtomicU64,
+    /// Shared state
+    state: Arc<LayerState>,
+    /// Time the layer was built (start of the uptime)
+    started: Instant,
+}
+
+impl Default for PrettyConsoleLayer {
+    fn default() -> Self {
+        Self {
+            format: PrettyFormatOptions {
+                link_dir: std::env::current_dir().ok(),
+                ..PrettyFormatOptions::default()
+            },
+            seq: AtomicU64::default(),
+            state: Arc::default(),
+            started: Instant::now(),
+        }
+    }
+}
+
+/// State of the layer, shared with its handles
+#[derive(Debug, Default)]
+struct LayerState {
+    /// Open spans, by span ID
+    open_spans: Mutex<HashMap<u64, OpenSpanInfo>>,
+    /// Number of spans considered for sampling
+    sampling_count: AtomicU64,
+    /// Number of spans skipped by sampling, since the last summary
+    sampling_skipped: AtomicU64,
+    /// Writers
+    writers: Mutex<Writers>,
+    /// ANSI escape codes are emitted (mirrors the format option, for the summaries)
+    ansi: AnsiFlag,
+    /// The legend has been printed
+    legend_printed: AtomicBool,
+    /// The startup banner has been printed
+    banner_printed: AtomicBool,
+    /// The header (TSV header row, or opening of the trace events array) has been printed
+    header_printed: AtomicBool,
+    /// Errors seen, for the error summary
+    errors: Mutex<Vec<String>>,
+    /// Number of events by target, for the target summary
+    target_counts: Mutex<HashMap<String, usize>>,
+    /// Busy time (in microseconds, excluding the children), by span path (eg `root;child`)
+    folded_stacks: Mutex<BTreeMap<String, u64>>,
+    /// Last root tree (structure hash, number of suppressed repeats, and root name)
+    last_tree: Mutex<Option<(u64, usize, &'static str)>>,
+    /// File capturing the spans and events
+    #[cfg(feature = "serde")]
+    recorder: Mutex<Option<std::fs::File>>,
+    /// A root span has been printed
+    root_printed: AtomicBool,
+}
+
+/// Flag of the ANSI escape codes (defaults to the absence of `NO_COLOR`, like the format option)
+#[derive(Debug)]
+struct AnsiFlag(AtomicBool);
+
+impl Default for AnsiFlag {
+    fn default() -> Self {
+        Self(AtomicBool::new(!no_color()))
+    }
+}
+
+impl AnsiFlag {
+    /// Returns true if the ANSI escape codes are emitted
+    fn get(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Sets if the ANSI escape codes are emitted
+    fn set(&self, ansi: bool) {
+        self.0.store(ansi, Ordering::Relaxed);
+    }
+}
+
+/// Applies a style to a text, unless the ANSI escape codes are disabled
+fn styled(text: &str, ansi: bool, style: impl FnOnce(&str) -> ColoredString) -> String {
+    if ansi {
+        style(text).to_string()
+    } else {
+        text.to_string()
+    }
+}
+
+/// A boxed writer
+pub type BoxWriter = Box<dyn Write + Send>;
+
+/// Default output stream
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputStream {
+    /// Standard output
+    Stdout,
+    /// Standard error
+    #[default]
+    Stderr,
+}
+
+impl OutputStream {
+    /// Returns the stream, locked
+    fn lock(self) -> Box<dyn Write> {
+        match self {
+            OutputStream::Stdout => Box::new(std::io::stdout().lock()),
+            OutputStream::Stderr => Box::new(std::io::stderr().lock()),
+        }
+    }
+}
+
+/// Writers overriding the default output stream
+#[derive(Default)]
+struct Writers {
+    /// Default output stream
+    stream: OutputStream,
+    /// Default writer, replacing the output stream
+    make_writer: Option<BoxMakeWriter>,
+    /// Writer for events
+    event: Option<BoxWriter>,
+    /// Writer for spans
+    span: Option<BoxWriter>,
+    /// Writers for events, by target prefix
+    routes: Vec<(String, BoxWriter)>,
+    /// Additional sinks, receiving all the records
+    sinks: Vec<Sink>,
+    /// Number of records written since the last flush
+    unflushed: usize,
+    /// Number of the last written line, if the lines are numbered
+    line_number: Option<usize>,
+    /// A write error has been reported
+    error_reported: bool,
+}
+
+thread_local! {
+    /// Serialization buffer of the thread, reused by the next record
+    static RECORD_BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+
+    /// Time of the last event of the thread
+    static LAST_EVENT: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// Max capacity of a recycled serialization buffer (larger buffers are dropped)
+const RECORD_BUFFER_MAX_CAPACITY: usize = 64 * 1024;
+
+/// Takes the serialization buffer of the thread (empty, and keeping the capacity of the previous records)
+///
+/// A nested serialization (eg the banner printed before a record) gets a new buffer.
+pub(super) fn take_buffer() -> Vec<u8> {
+    RECORD_BUFFER
+        .try_with(|buf| std::mem::take(&mut *buf.borrow_mut()))
+        .unwrap_or_default()
+}
+
+/// Returns a serialization buffer to the thread, once its record is written
+pub(super) fn recycle_buffer(mut buf: Vec<u8>) {
+    if buf.capacity() > RECORD_BUFFER_MAX_CAPACITY {
+        return;
+    }
+    buf.clear();
+    let _ = RECORD_BUFFER.try_with(|recycled| {
+        let mut recycled = recycled.borrow_mut();
+        if buf.capacity() > recycled.capacity() {
+            *recycled = buf;
+        }
+    });
+}
+
+/// Width of the line numbers (right-aligned)
+const LINE_NUMBER_WIDTH: usize = 5;
+
+/// An additional output, receiving all the records
+struct Sink {
+    /// Writer
+    writer: BoxWriter,
+    /// ANSI escape codes are kept (otherwise they are stripped)
+    ansi: bool,
+}
+
+/// Writes a record, and its trailing newline
+fn write_line(writer: &mut (impl Write + ?Sized), buf: &[u8], flush: bool) -> std::io::Result<()> {
+    writer.write_all(buf)?;
+    writer.write_all(b"\n")?;
+    if flush {
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+impl Writers {
+    /// Reports a write (or serialization) error, once, on stderr
+    ///
+    /// A broken pipe (eg the output is piped to `head`) is not reported. Records are never written
+    /// to the fallback stream, so the errors never unwind into the instrumented code.
+    fn report_error(&mut self, err: std::io::Error) {
+        if err.kind() == std::io::ErrorKind::BrokenPipe || self.error_reported {
+            return;
+        }
+        self.error_reported = true;
+        let _ = writeln!(
+            std::io::stderr(),
+            "tracing-ext: failed to write a record: {err}"
+        );
+    }
+
+    /// Flushes all the writers
+    fn flush(&mut self) {
+        let writers = self
+            .event
+            .iter_mut()
+            .chain(self.span.iter_mut())
+            .chain(self.routes.iter_mut().map(|(_, w)| w))
+            .chain(self.sinks.iter_mut().map(|s| &mut s.writer));
+        for writer in writers {
+            let _ = writer.flush();
+        }
+        match &self.make_writer {
+            Some(make_writer) => {
+                let _ = make_writer.make_writer().flush();
+            }
+            None => {
+                let _ = self.stream.lock().flush();
+            }
+        }
+        self.unflushed = 0;
+    }
+
+    /// Writes a record (1 or more lines), and its trailing newline
+    ///
+    /// The writers are behind the layer lock, so the lines of a record are never interleaved with
+    /// the lines of records from other threads.
+    fn write(&mut self, kind: RecordKind, buf: &[u8], flush_policy: FlushPolicy) {
+        let numbered;
+        let buf = match &mut self.line_number {
+            Some(line_number) => {
+                numbered = number_lines(buf, line_number);
+                numbered.as_slice()
+            }
+            None => buf,
+        };
+
+        let writer = match kind {
+            RecordKind::Span => self.span.as_mut(),
+            RecordKind::Event(target) => self
+                .routes
+                .iter_mut()
+                .filter(|(prefix, _)| target.starts_with(prefix.as_str()))
+                .max_by_key(|(prefix, _)| prefix.len())
+                .map(|(_, writer)| writer)
+                .or(self.event.as_mut()),
+        };
+        let result = match writer {
+            Some(writer) => write_line(writer, buf, flush_policy == FlushPolicy::PerLine),
+            None => {
+                // the default writer is acquired once per record
+                let mut writer = match &self.make_writer {
+                    Some(make_writer) => make_writer.make_writer(),
+                    None => self.stream.lock(),
+                };
+                let flush = flush_policy == FlushPolicy::PerLine && self.make_writer.is_some();
+                write_line(&mut writer, buf, flush)
+            }
+        };
+        if let Err(err) = result {
+            self.report_error(err);
+        }
+
+        if !self.sinks.is_empty() {
+            let mut stripped = None;
+            let mut sink_error = None;
+            for sink in &mut self.sinks {
+                let buf = if sink.ansi {
+                    buf
+                } else {
+                    stripped.get_or_insert_with(|| strip_ansi(buf)).as_slice()
+                };
+                let flush = flush_policy == FlushPolicy::PerLine;
+                if let Err(err) = write_line(&mut sink.writer, buf, flush) {
+                    sink_error.get_or_insert(err);
+                }
+            }
+            if let Some(err) = sink_error {
+                self.report_error(err);
+            }
+        }
+
+        self.unflushed += 1;
+        if let FlushPolicy::Batched(n) = flush_policy {
+            if self.unflushed >= n {
+                self.flush();
+            }
+        }
+    }
+}
+
+/// Prefixes each line of a record with its (right-aligned) number, continuing from the last number
+fn number_lines(buf: &[u8], line_number: &mut usize) -> Vec<u8> {
+    let mut numbered = Vec::with_capacity(buf.len() + LINE_NUMBER_WIDTH + 1);
+    for (i, line) in buf.split(|b| *b == b'\n').enumerate() {
+        if i > 0 {
+            numbered.push(b'\n');
+        }
+        *line_number += 1;
+        write!(numbered, "{:>w$} ", line_number, w = LINE_NUMBER_WIDTH).unwrap();
+        numbered.extend_from_slice(line);
+    }
+    numbered
+}
+
+/// Policy to flush the writers
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Writers are flushed after each record
+    #[default]
+    PerLine,
+    /// Writers are flushed every N records
+    Batched(usize),
+    /// Writers are only flushed with [PrettyConsoleHandle::flush]
+    Manual,
+}
+
+/// Labels and colors of the event levels
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LevelStyle {
+    /// Label and color, from TRACE to ERROR
+    levels: [(String, Color); 5],
+}
+
+impl Default for LevelStyle {
+    fn default() -> Self {
+        Self {
+            levels: [
+                ("TRACE".to_string(), Color::Magenta),
+                ("DEBUG".to_string(), Color::Blue),
+                ("INFO".to_string(), Color::Green),
+                ("WARN".to_string(), Color::Yellow),
+                ("ERROR".to_string(), Color::Red),
+            ],
+        }
+    }
+}
+
+impl LevelStyle {
+    /// Returns the index of a level
+    fn index(level: Level) -> usize {
+        match level {
+            Level::TRACE => 0,
+            Level::DEBUG => 1,
+            Level::INFO => 2,
+            Level::WARN => 3,
+            Level::ERROR => 4,
+        }
+    }
+
+    /// Returns the label of a level
+    pub fn label(&self, level: Level) -> &str {
+        &self.levels[Self::index(level)].0
+    }
+
+    /// Returns the color of a level
+    pub fn color(&self, level: Level) -> Color {
+        self.levels[Self::index(level)].1
+    }
+
+    /// Sets the label of a level
+    pub fn set_label(&mut self, level: Level, label: impl Into<String>) {
+        self.levels[Self::index(level)].0 = label.into();
+    }
+
+    /// Sets the color of a level
+    pub fn set_color(&mut self, level: Level, color: Color) {
+        self.levels[Self::index(level)].1 = color;
+    }
+
+    /// Returns a label colored with the color of a level
+    fn paint(&self, label: &str, level: Level) -> ColoredString {
+        label.color(self.color(level))
+    }
+}
+
+impl std::fmt::Debug for Writers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Writers")
+            .field("stream", &self.stream)
+            .field("make_writer", &self.make_writer.is_some())
+            .field("line_number", &self.line_number)
+            .field("event", &self.event.is_some())
+            .field("span", &self.span.is_some())
+            .field(
+                "routes",
+                &self.routes.iter().map(|(p, _)| p).collect::<Vec<_>>(),
+            )
+            .field("sinks", &self.sinks.len())
+            .finish()
+    }
+}
+
+/// Kind of output record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecordKind<'a> {
+    /// Span entry or exit
+    Span,
+    /// Event (with its target)
+    Event(&'a str),
+}
+
+/// A handle to a [PrettyConsoleLayer]
+///
+/// The handle remains usable after the layer has been moved into a subscriber.
+///
+/// ```
+/// use tracing_ext::sub::PrettyConsoleLayer;
+/// use tracing_subscriber::prelude::*;
+///
+/// let layer = PrettyConsoleLayer::default();
+/// let handle = layer.handle();
+/// let _subscriber = tracing_subscriber::registry().with(layer);
+///
+/// for span in handle.open_spans() {
+///     println!("{} open for {:?}", span.name, span.elapsed());
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct PrettyConsoleHandle {
+    /// Shared state
+    state: Arc<LayerState>,
+}
+
+impl PrettyConsoleHandle {
+    /// Sets the writer for events, and returns the previous writer
+    ///
+    /// The writers are swapped under the same lock used for writing, so a record is never split
+    /// between the previous and the new writer. The previous writer is flushed before being returned.
+    pub fn set_event_writer(&self, writer: impl Write + Send + 'static) -> Option<BoxWriter> {
+        let mut writers = self.state.writers.lock().unwrap();
+        Self::swap_writer(&mut writers.event, Some(Box::new(writer)))
+    }
+
+    /// Sets the writer for spans, and returns the previous writer
+    ///
+    /// See [set_event_writer](Self::set_event_writer) for the guarantees during a swap.
+    pub fn set_span_writer(&self, writer: impl Write + Send + 'static) -> Option<BoxWriter> {
+        let mut writers = self.state.writers.lock().unwrap();
+        Self::swap_writer(&mut writers.span, Some(Box::new(writer)))
+    }
+
+    /// Resets the writer for events to the output stream, and returns the previous writer
+    pub fn reset_event_writer(&self) -> Option<BoxWriter> {
+        let mut writers = self.state.writers.lock().unwrap();
+        Self::swap_writer(&mut writers.event, None)
+    }
+
+    /// Resets the writer for spans to the output stream, and returns the previous writer
+    pub fn reset_span_writer(&self) -> Option<BoxWriter> {
+        let mut writers = self.state.writers.lock().unwrap();
+        Self::swap_writer(&mut writers.span, None)
+    }
+
+    /// Adds a sink, which receives all the records (spans and events)
+    ///
+    /// See [PrettyConsoleLayer::sink].
+    pub fn add_sink(&self, writer: impl Write + Send + 'static, ansi: bool) {
+        self.state.writers.lock().unwrap().sinks.push(Sink {
+            writer: Box::new(writer),
+            ansi,
+        });
+    }
+
+    /// Flushes all the writers
+    pub fn flush(&self) {
+        self.state.writers.lock().unwrap().flush();
+    }
+
+    /// Writes the folded stacks of the closed spans, 1 line per span path (eg `root;child 1234`)
+    ///
+    /// The count is the busy time of the spans (in microseconds), excluding the busy time of their
+    /// children. The lines can be turned into a flamegraph (eg with `inferno-flamegraph`).
+    /// See [PrettyConsoleLayer::folded_stacks].
+    pub fn write_folded_stacks(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        let folded_stacks = self.state.folded_stacks.lock().unwrap();
+        for (path, busy_us) in folded_stacks.iter() {
+            writeln!(writer, "{path} {busy_us}")?;
+        }
+        writer.flush()
+    }
+
+    /// Swaps a writer, flushing the previous one
+    fn swap_writer(slot: &mut Option<BoxWriter>, writer: Option<BoxWriter>) -> Option<BoxWriter> {
+        let mut previous = std::mem::replace(slot, writer);
+        if let Some(previous) = &mut previous {
+            let _ = previous.flush();
+        }
+        previous
+    }
+}
+
+/// Build info, to correlate the logs with a build
+///
+/// ```
+/// use tracing_ext::sub::{BuildInfo, PrettyConsoleLayer};
+///
+/// let layer = PrettyConsoleLayer::default().build_info(BuildInfo {
+///     version: env!("CARGO_PKG_VERSION").to_string(),
+///     commit: option_env!("GIT_COMMIT").map(String::from),
+/// });
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BuildInfo {
+    /// Version (eg `1.2.0`)
+    pub version: String,
+    /// Commit (eg the git commit hash)
+    pub commit: Option<String>,
+}
+
+impl BuildInfo {
+    /// Returns the build fields (`version` and `commit`)
+    fn fields(&self) -> Vec<(&'static str, &str)> {
+        let mut fields = vec![("version", self.version.as_str())];
+        if let Some(commit) = &self.commit {
+            fields.push(("commit", commit.as_str()));
+        }
+        fields
+    }
+}
+
+/// Info about an open span
+#[derive(Debug, Clone)]
+pub struct OpenSpanInfo {
+    /// Span ID
+    pub id: u64,
+    /// Span name
+    pub name: &'static str,
+    /// Instant when the span was created
+    pub created: Instant,
+}
+
+impl OpenSpanInfo {
+    /// Returns the time elapsed since the span was created
+    pub fn elapsed(&self) -> Duration {
+        self.created.elapsed()
+    }
+}
+
+impl PrettyConsoleHandle {
+    /// Returns the open spans, oldest first
+    pub fn open_spans(&self) -> Vec<OpenSpanInfo> {
+        let mut spans: Vec<_> = self
+            .state
+            .open_spans
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect();
+        spans.sort_by_key(|span| (span.created, span.id));
+        spans
+    }
+
+    /// Returns a guard printing the summaries when dropped (e.g. at the end of `main`)
+    pub fn summary_guard(&self) -> SummaryGuard {
+        SummaryGuard {
+            state: self.state.clone(),
+        }
+    }
+}
+
+/// A guard printing the summaries (e.g. the errors, or the events by target) when dropped
+///
+/// ```
+/// use tracing_ext::sub::PrettyConsoleLayer;
+/// use tracing_subscriber::prelude::*;
+///
+/// let layer = PrettyConsoleLayer::default().error_summary_on_exit(true);
+/// let _guard = layer.summary_guard();
+/// tracing_subscriber::registry().with(layer).init();
+///
+/// tracing::error!("something failed");
+/// // => `ERRORS:` followed by the errors, when `_guard` is dropped
+/// ```
+#[derive(Debug)]
+#[must_use = "the summaries are printed when the guard is dropped"]
+pub struct SummaryGuard {
+    /// Shared state
+    state: Arc<LayerState>,
+}
+
+impl Drop for SummaryGuard {
+    fn drop(&mut self) {
+        let last_tree = self.state.last_tree.lock().unwrap().take();
+        let skipped = self.state.sampling_skipped.swap(0, Ordering::Relaxed);
+        let errors = std::mem::take(&mut *self.state.errors.lock().unwrap());
+        let target_counts = std::mem::take(&mut *self.state.target_counts.lock().unwrap());
+        let ansi = self.state.ansi.get();
+        let mut writers = self.state.writers.lock().unwrap();
+        if let Some((_, repeats @ 1.., name)) = last_tree {
+            let buf = repeats_line(repeats, name, ansi);
+            writers.write(RecordKind::Span, buf.as_bytes(), FlushPolicy::PerLine);
+        }
+        if skipped > 0 {
+            let buf = sampling_line(skipped, ansi);
+            writers.write(RecordKind::Span, buf.as_bytes(), FlushPolicy::PerLine);
+        }
+        if !errors.is_empty() {
+            let mut buf: Vec<u8> = vec![];
+            write!(buf, "{}", styled("ERRORS:", ansi, |t| t.red().bold())).unwrap();
+            for error in errors {
+                write!(buf, "\n  {error}").unwrap();
+            }
+            // NB: the messages may contain escape codes
+            if !ansi {
+                buf = strip_ansi(&buf);
+            }
+            writers.write(RecordKind::Event(""), &buf, FlushPolicy::PerLine);
+        }
+        if !target_counts.is_empty() {
+            let buf = target_tree(&target_counts, ansi);
+            writers.write(RecordKind::Event(""), buf.as_bytes(), FlushPolicy::PerLine);
+        }
+        writers.flush();
+    }
+}
+
+/// Returns the line with the number of spans skipped by sampling
+fn sampling_line(skipped: u64, ansi: bool) -> String {
+    styled(
+        &format!("({skipped} spans skipped by sampling)"),
+        ansi,
+        |t| t.dimmed(),
+    )
+}
+
+/// Returns the tree of the event counts by target (under a `TARGETS:` header)
+///
+/// The targets are split on `::`, and each node shows the count of its whole subtree, eg
+///
+/// ```text
+/// TARGETS:
+///   my_app 12
+///     db 5
+///     http 7
+/// ```
+fn target_tree(counts: &HashMap<String, usize>, ansi: bool) -> String {
+    let mut nodes: BTreeMap<Vec<&str>, usize> = BTreeMap::new();
+    for (target, count) in counts {
+        let segments: Vec<&str> = target.split("::").collect();
+        for depth in 1..=segments.len() {
+            *nodes.entry(segments[..depth].to_vec()).or_default() += count;
+        }
+    }
+
+    let mut tree = styled("TARGETS:", ansi, |t| t.bold());
+    for (path, count) in nodes {
+        let indent = "  ".repeat(path.len());
+        let name = path.last().copied().unwrap_or_default();
+        let count = styled(&count.to_string(), ansi, |t| t.dimmed());
+        tree.push_str(&format!("\n{indent}{name} {count}"));
+    }
+    tree
+}
+
+/// Returns the line collapsing the repeats of a span tree (eg `(x12) {root}`)
+fn repeats_line(repeats: usize, name: &str, ansi: bool) -> String {
+    styled(&format!("(x{repeats}) {{{name}}}"), ansi, |t| t.dimmed())
+}
+
+/// Position of the child spans relative to the events of their parent (wrapped mode)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ChildrenPosition {
+    /// Children are printed after the parent events
+    #[default]
+    After,
+    /// Children are printed before the parent events
+    Before,
+    /// Children and events are printed in chronological order
+    Interleaved,
+}
+
+/// Rendering of field values containing newlines
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MultilineStyle {
+    /// Newlines are escaped (`\n`)
+    #[default]
+    Escape,
+    /// The value continues on indented lines under the key
+    Indent,
+    /// The value is wrapped in a fenced block (```` ``` ````)
+    Fence,
+}
+
+/// Placement of the span attributes
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AttrPlacement {
+    /// Attributes are printed on the span entry
+    #[default]
+    Enter,
+    /// Attributes are printed on the span exit (with their final values)
+    Exit,
+    /// Attributes are printed on the span entry and exit
+    Both,
+    /// Attributes are not printed
+    None,
+}
+
+/// Metadata field of the span entries and events (pretty format)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetaField {
+    /// Timestamp (and schedule delay of the spans)
+    Time,
+    /// Span info (ID, name of the event span, and ancestor IDs of the spans)
+    Span,
+    /// Target
+    Target,
+    /// File info
+    File,
+}
+
+/// Default order of the metadata fields
+const METADATA_ORDER_DEFAULT: [MetaField; 4] = [
+    MetaField::Time,
+    MetaField::Span,
+    MetaField::Target,
+    MetaField::File,
+];
+
+/// Rendering of the file info when the metadata has no file
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MissingFileInfo {
+    /// The file field is omitted
+    #[default]
+    Omit,
+    /// The file field is rendered as `file: <unknown>`
+    Unknown,
+}
+
+/// Timestamp of the spans and events
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampMode {
+    /// Wall clock time (or offset from the epoch, if set)
+    #[default]
+    WallClock,
+    /// Time elapsed since the layer was created (eg `+1.234s`)
+    Uptime,
+    /// No timestamp
+    None,
+}
+
+/// Output format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Pretty print
+    Pretty,
+    /// RFC 5424 syslog lines (events only)
+    Syslog,
+    /// Tab-separated values, with a header row (events only)
+    Tsv,
+    /// Logfmt lines (events only)
+    Logfmt,
+    /// YAML documents, one per span tree (wrapped mode)
+    #[cfg(feature = "serde")]
+    Yaml,
+    /// JSON lines (events only)
+    #[cfg(feature = "serde")]
+    Json,
+    /// Trace events, for chrome://tracing or Perfetto (streaming, even in the wrapped mode)
+    #[cfg(feature = "serde")]
+    ChromeTrace,
+}
+
+impl OutputFormat {
+    /// Checks if the spans and events are written as they happen, whatever the wrapped mode
+    fn is_streamed(self) -> bool {
+        #[cfg(feature = "serde")]
+        if self == OutputFormat::ChromeTrace {
+            return true;
+        }
+        false
+    }
+}
+
+/// Style of the span breadcrumb printed before an event message
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BreadcrumbStyle {
+    /// Span names (eg `[root>child]`)
+    Names,
+    /// Span IDs (eg `[1>3>5]`)
+    Ids,
+    /// No breadcrumb
+    #[default]
+    None,
+}
+
+/// A user-provided callback
+pub struct Callback<F: ?Sized>(pub Arc<F>);
+
+impl<F: ?Sized> Clone for Callback<F> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<F: ?Sized> std::fmt::Debug for Callback<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Callback")
+    }
+}
+
+/// Provider of context fields
+pub type ContextFieldsFn = dyn Fn() -> Vec<(String, String)> + Send + Sync;
+
+/// Returns the minimum level of the events within a span, from its attributes
+pub type SpanEventLevelFn = dyn Fn(&Fields) -> Level + Send + Sync;
+
+/// Returns the color of a numeric field value
+pub type NumericValueStyleFn = dyn Fn(f64) -> Option<Color> + Send + Sync;
+
+/// Returns the duration budget of a span, from its name
+pub type SpanBudgetFn = dyn Fn(&str) -> Option<Duration> + Send + Sync;
+
+/// Checks if an event is in focus, from its message and fields
+pub type FocusFn = dyn Fn(&str, &Fields) -> bool + Send + Sync;
+
+/// Formatting options (for spans and events)
+///
+/// The options are usually set with the [PrettyConsoleLayer] builder methods, and can also be built
+/// beforehand (eg shared between layers). New options may be added, so the struct is built from
+/// its default value.
+///
+/// ```
+/// use tracing_ext::sub::{PrettyConsoleLayer, PrettyFormatOptions, TimestampMode};
+///
+/// let mut options = PrettyFormatOptions::default();
+/// options.wrapped = true;
+/// options.show_target = false;
+/// options.indent = 4;
+/// let layer = PrettyConsoleLayer::with_options(options.clone()).show_time(false);
+/// assert!(layer.options().wrapped);
+/// assert_eq!(layer.options().timestamp_mode, TimestampMode::None);
+/// ```
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct PrettyFormatOptions {
+    /// Defines if the display is wrapped
+    pub wrapped: bool,
+    /// If true, spans and events are printed in 1 line
+    pub oneline: bool,
+    /// Time format
+    pub time_format: &'static [time::format_description::FormatItem<'static>],
+    /// The span is shown (enter and exit info)
+    pub events_only: bool,
+    /// Timestamp of the spans and events
+    pub timestamp_mode: TimestampMode,
+    /// The target is shown
+    pub show_target: bool,
+    /// The file info is shown
+    pub show_file_info: bool,
+    /// Shows the span info
+    pub show_span_info: bool,
+    /// Indentation (x spaces) - invalid if the `oneline` option is set
+    pub indent: usize,
+    /// Events emitted while the thread is panicking are marked
+    pub mark_panics: bool,
+    /// Indentation (x spaces) per tree level - overrides `indent` for the tree indentation
+    pub indent_fn: Option<Callback<dyn Fn(usize) -> usize + Send + Sync>>,
+    /// Indentation unit per tree level (eg a tab or `│  `) - overrides `indent` for the tree
+    /// indentation (ignored if empty)
+    pub indent_str: Option<String>,
+    /// Name of the event field holding the message
+    pub message_field: &'static str,
+    /// Output format
+    pub output_format: OutputFormat,
+    /// Syslog facility code
+    pub syslog_facility: u8,
+    /// Syslog app name (defaults to the executable name)
+    pub syslog_app_name: Option<String>,
+    /// Position of the child spans relative to the parent events
+    pub children_position: ChildrenPosition,
+    /// Style of the span breadcrumb before event messages
+    pub breadcrumb_style: BreadcrumbStyle,
+    /// Fraction of spans which are printed (0.0 to 1.0)
+    pub span_sample_rate: f64,
+    /// A legend is printed before the first record
+    pub legend: bool,
+    /// The span duration is also printed as a `duration_ns` field
+    pub machine_duration: bool,
+    /// Indentation (x spaces) added to every line
+    pub base_indent: usize,
+    /// Prefix of the span attributes and event fields names
+    pub field_prefix: String,
+    /// Span attributes identical to the parent attributes are hidden (wrapped mode)
+    pub hide_redundant_attrs: bool,
+    /// Event icons, by target prefix
+    pub target_icons: HashMap<String, char>,
+    /// Policy to flush the writers
+    pub flush_policy: FlushPolicy,
+    /// Span trees without warnings or errors are collapsed into a summary line (wrapped mode)
+    pub collapse_successful: bool,
+    /// Symbols of the span entry and exit, replacing the braces (wrapped mode)
+    pub span_symbols: Option<(String, String)>,
+    /// The span entry and exit symbols are colored (green and blue)
+    pub color_span_symbols: bool,
+    /// Provider of context fields added to each event
+    pub context_fields: Option<Callback<ContextFieldsFn>>,
+    /// A bar shows the span duration relative to its parent duration (wrapped mode)
+    pub duration_bars: bool,
+    /// Width of the duration bars (x characters)
+    pub duration_bar_width: usize,
+    /// The event target is hidden when it is the same as its span target
+    pub hide_redundant_target: bool,
+    /// Shows the delay between the span creation and its first enter
+    pub show_schedule_delay: bool,
+    /// Separator line printed between root span trees
+    pub root_separator: Option<String>,
+    /// Rendering of multi-line field values
+    pub multiline_field_style: MultilineStyle,
+    /// Collects the errors, printed by the summary guard
+    pub error_summary_on_exit: bool,
+    /// Epoch of the relative times (otherwise, the time is absolute)
+    pub time_epoch: Option<time::OffsetDateTime>,
+    /// Minimum level of the events within a span, from the span attributes
+    pub span_event_level: Option<Callback<SpanEventLevelFn>>,
+    /// Each line starts with a kind marker (`S` for spans, `E` for events)
+    pub kind_marker: bool,
+    /// Fields with a unit, `None` or empty value are shown
+    pub show_unit_fields: bool,
+    /// The first line of an event shows the time, level, span and message
+    pub header_line: bool,
+    /// Events are printed by another layer (only spans are printed)
+    pub events_from_other_layer: bool,
+    /// Color of the numeric event field values
+    pub numeric_value_style: Option<Callback<NumericValueStyleFn>>,
+    /// Consecutive root trees with the same structure are collapsed (wrapped mode)
+    pub dedupe_trees: bool,
+    /// Placement of the span attributes
+    pub attr_placement: AttrPlacement,
+    /// Indentation of the fields, relative to their span or event (defaults to the indentation)
+    pub field_indent: Option<usize>,
+    /// Spans which are never entered are hidden
+    pub hide_unentered_spans: bool,
+    /// Duration budget of the spans
+    pub span_budget: Option<Callback<SpanBudgetFn>>,
+    /// Build info, printed in a startup banner and added to the structured records
+    pub build_info: Option<BuildInfo>,
+    /// Fields shown on the span entries, in the oneline mode (all if not set)
+    pub span_oneline_fields: Option<Vec<String>>,
+    /// The events of a span are grouped by level (wrapped mode)
+    pub group_events_by_level: bool,
+    /// Rendering of the file info when the metadata has no file
+    pub missing_file_info: MissingFileInfo,
+    /// Spans without events nor children are printed on a single line (wrapped mode)
+    pub collapse_empty_spans: bool,
+    /// The event counts by target are summarized when the summary guard is dropped
+    pub target_summary_on_exit: bool,
+    /// Field overriding the displayed level, with the mapping of its (lowercase) values to levels
+    pub severity_field: Option<(String, HashMap<String, Level>)>,
+    /// ANSI escape codes (colors and styles) are emitted (default: true, unless `NO_COLOR` is set)
+    pub ansi: bool,
+    /// Events emitted on a span which is not entered are marked
+    pub warn_late_events: bool,
+    /// The IDs of the span ancestors are shown on the span entry
+    pub show_ancestor_ids: bool,
+    /// Order of the metadata fields (fields not listed are not shown)
+    pub metadata_order: Vec<MetaField>,
+    /// The ID of the tokio task is shown (requires the `tokio` feature)
+    pub show_task_id: bool,
+    /// Timestamps are shown in local time (instead of UTC)
+    pub use_local_time: bool,
+    /// Only the span trees containing an event in focus are shown (wrapped mode)
+    pub focus: Option<Callback<FocusFn>>,
+    /// Durations are shown in a unit depending on their magnitude (otherwise in microseconds)
+    pub duration_human: bool,
+    /// Most verbose level printed (spans and events)
+    pub max_level: Option<Level>,
+    /// Target prefixes of the printed spans and events (all targets if not set)
+    pub target_allowlist: Option<Vec<String>>,
+    /// Target prefixes of the spans and events which are not printed
+    pub target_denylist: Vec<String>,
+    /// The name and ID of the thread which emitted an event are shown
+    pub show_thread: bool,
+    /// Max number of characters of a field value (longer values are truncated with `…`)
+    pub field_value_max_len: Option<usize>,
+    /// Names of the fields whose values are replaced with `***`
+    pub redacted_fields: Vec<&'static str>,
+    /// The attributes of the event span and its ancestors are shown on the event
+    pub inherit_span_fields: bool,
+    /// The busy time (while entered) and idle time of the spans are shown on the span exit
+    pub show_busy_idle: bool,
+    /// The time since the previous event of the thread is shown (eg `+42ms`)
+    pub show_delta: bool,
+    /// The wrapped tree is drawn with guides (`├─`, `└─`, `│`)
+    pub tree_guides: bool,
+    /// Spans faster than this duration are omitted from the tree, their events and children are
+    /// printed within the parent (wrapped mode)
+    pub collapse_faster_than: Option<Duration>,
+    /// Fast spans with events at this level (or more severe) are not collapsed
+    pub collapse_keep_level: Level,
+    /// Spans slower than this duration are marked on their exit line (`⚠ SLOW`, in red)
+    pub slow_threshold: Option<Duration>,
+    /// The number of events within the span (and its descendants) is shown on the exit line
+    pub show_event_count: bool,
+    /// The busy time of the spans is accumulated by span path, for the folded stacks
+    pub folded_stacks: bool,
+    /// Events are shown as `LEVEL message key=value ...`, without metadata, and spans are shown as
+    /// 1 line (overrides the oneline mode)
+    pub compact: bool,
+    /// Marker of the span entries, in the streaming mode (default: `-->`)
+    pub span_enter_marker: String,
+    /// Marker of the span exits, in the streaming mode (default: `<--`)
+    pub span_exit_marker: String,
+    /// Brackets around the span names (default: `{` and `}`)
+    pub span_name_brackets: (String, String),
+    /// Labels and colors of the event levels
+    pub level_style: LevelStyle,
+    /// Prefix stripped from the file paths (eg the workspace directory)
+    pub file_prefix: Option<String>,
+    /// The file info is a terminal hyperlink (OSC 8) to the source file
+    pub file_hyperlink: bool,
+    /// Directory of the relative file paths of the hyperlinks (current directory when the layer is
+    /// built)
+    link_dir: Option<PathBuf>,
+}
+
+/// Returns the `file://` URL of a path (percent-encoded)
+pub(crate) fn file_url(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let mut url = String::with_capacity(path.len() + 8);
+    url.push_str("file://");
+    // NB: Windows paths start with the drive letter (eg `file:///C:/dir`)
+    if !path.starts_with('/') {
+        url.push('/');
+    }
+    for b in path.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~/:".contains(&b) {
+            url.push(b as char);
+        } else {
+            url.push_str(&format!("%{b:02X}"));
+        }
+    }
+    url
+}
+
+/// Returns the ID of the current tokio task, if called within a task
+#[cfg(feature = "tokio")]
+fn current_task_id() -> Option<String> {
+    tokio::task::try_id().map(|id| id.to_string())
+}
+
+/// Returns the ID of the current tokio task (always `None` without the `tokio` feature)
+#[cfg(not(feature = "tokio"))]
+fn current_task_id() -> Option<String> {
+    None
+}
+
+/// Returns the name and ID of the current thread (eg `main (ThreadId(1))`)
+fn current_thread() -> String {
+    let thread = std::thread::current();
+    format!(
+        "{} ({:?})",
+        thread.name().unwrap_or("<unnamed>"),
+        thread.id()
+    )
+}
+
+/// Returns the time elapsed since the previous event of the thread (zero for the first event)
+fn event_delta(now: Instant) -> Duration {
+    LAST_EVENT
+        .replace(Some(now))
+        .map(|last| now.saturating_duration_since(last))
+        .unwrap_or_default()
+}
+
+/// Returns the local offset, determined once (UTC if it cannot be determined)
+fn local_offset() -> time::UtcOffset {
+    static LOCAL_OFFSET: OnceLock<time::UtcOffset> = OnceLock::new();
+    *LOCAL_OFFSET
+        .get_or_init(|| time::UtcOffset::current_local_offset().unwrap_or(time::UtcOffset::UTC))
+}
+
+/// Checks if the `NO_COLOR` environment variable is set (to a non-empty value, see <https://no-color.org>)
+fn no_color() -> bool {
+    std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty())
+}
+
+impl Default for PrettyFormatOptions {
+    fn default() -> Self {
+        Self {
+            wrapped: false,
+            oneline: false,
+            time_format: TIME_FORMAT_DEFAULT,
+            events_only: false,
+            timestamp_mode: TimestampMode::default(),
+            show_target: true,
+            show_file_info: true,
+            show_span_info: true,
+            indent: 6,
+            mark_panics: false,
+            indent_fn: None,
+            indent_str: None,
+            message_field: MESSAGE_FIELD_DEFAULT,
+            output_format: OutputFormat::Pretty,
+            syslog_facility: syslog::SYSLOG_FACILITY_DEFAULT,
+            syslog_app_name: None,
+            children_position: ChildrenPosition::default(),
+            breadcrumb_style: BreadcrumbStyle::default(),
+            span_sample_rate: 1.0,
+            legend: false,
+            machine_duration: false,
+            base_indent: 0,
+            field_prefix: String::new(),
+            hide_redundant_attrs: false,
+            target_icons: HashMap::new(),
+            flush_policy: FlushPolicy::default(),
+            collapse_successful: false,
+            span_symbols: None,
+            color_span_symbols: false,
+            context_fields: None,
+            duration_bars: false,
+            duration_bar_width: 10,
+            hide_redundant_target: false,
+            show_schedule_delay: false,
+            root_separator: None,
+            multiline_field_style: MultilineStyle::default(),
+            error_summary_on_exit: false,
+            time_epoch: None,
+            span_event_level: None,
+            kind_marker: false,
+            show_unit_fields: true,
+            header_line: false,
+            events_from_other_layer: false,
+            numeric_value_style: None,
+            dedupe_trees: false,
+            attr_placement: AttrPlacement::default(),
+            field_indent: None,
+            hide_unentered_spans: false,
+            span_budget: None,
+            build_info: None,
+            span_oneline_fields: None,
+            group_events_by_level: false,
+            missing_file_info: MissingFileInfo::default(),
+            collapse_empty_spans: false,
+            target_summary_on_exit: false,
+            severity_field: None,
+            ansi: !no_color(),
+            warn_late_events: false,
+            show_ancestor_ids: false,
+            metadata_order: METADATA_ORDER_DEFAULT.to_vec(),
+            show_task_id: false,
+            use_local_time: false,
+            focus: None,
+            duration_human: true,
+            max_level: None,
+            target_allowlist: None,
+            target_denylist: vec![],
+            show_thread: false,
+            field_value_max_len: None,
+            redacted_fields: vec![],
+            inherit_span_fields: false,
+            show_busy_idle: false,
+            show_delta: false,
+            tree_guides: false,
+            collapse_faster_than: None,
+            collapse_keep_level: Level::WARN,
+            slow_threshold: None,
+            show_event_count: false,
+            folded_stacks: false,
+            compact: false,
+            span_enter_marker: "-->".to_string(),
+            span_exit_marker: "<--".to_string(),
+            span_name_brackets: ("{".to_string(), "}".to_string()),
+            level_style: LevelStyle::default(),
+            file_prefix: None,
+            file_hyperlink: false,
+            link_dir: None,
+        }
+    }
+}
+
+impl PrettyFormatOptions {
+    /// Returns the tree indentation (x spaces) for a tree level
+    fn tree_indent(&self, tree_level: usize) -> usize {
+        match (&self.indent_fn, self.indent_unit()) {
+            (Some(indent_fn), _) => (0..tree_level).map(|level| (indent_fn.0)(level)).sum(),
+            (None, Some(unit)) => tree_level * unit.chars().count(),
+            (None, None) => tree_level * self.indent,
+        }
+    }
+
+    /// Draws the tree guides in the tree indentation of the lines of a record (wrapped mode)
+    ///
+    /// `guides` holds, for each tree level of the record, if the record (or its ancestor at that
+    /// level) has following siblings. If `connector` is set, the first line gets a connector, the
+    /// other lines continue the vertical guides. Only blank indentations are replaced.
+    fn draw_tree_guides(&self, buf: Vec<u8>, guides: &[bool], connector: bool) -> Vec<u8> {
+        if !self.tree_guides || !self.wrapped || guides.is_empty() || buf.is_empty() {
+            return buf;
+        }
+        let skip = self.base_indent + if self.kind_marker { 2 } else { 0 };
+        let column = |level: usize, first: char| {
+            let width = self.tree_indent(level + 1) - self.tree_indent(level);
+            let fill = if matches!(first, '├' | '└') {
+                '─'
+            } else {
+                ' '
+            };
+            let mut column = String::with_capacity(width * 3);
+            for i in 0..width {
+                column.push(match i {
+                    0 => first,
+                    i if i + 1 == width && width > 2 => ' ',
+                    _ => fill,
+                });
+            }
+            column
+        };
+        let prefix = |head: bool| -> String {
+            let last = guides.len() - 1;
+            (0..guides.len())
+                .map(|level| match (level == last && head, guides[level]) {
+                    (true, true) => column(level, '├'),
+                    (true, false) => column(level, '└'),
+                    (false, true) => column(level, '│'),
+                    (false, false) => column(level, ' '),
+                })
+                .collect()
+        };
+        let width = self.tree_indent(guides.len());
+
+        let mut guided = take_buffer();
+        for (i, line) in buf.split(|b| *b == b'\n').enumerate() {
+            if i > 0 {
+                guided.push(b'\n');
+            }
+            let indent = line.get(skip..skip + width);
+            if indent.is_some_and(|indent| indent.iter().all(|b| *b == b' ')) {
+                guided.extend_from_slice(&line[..skip]);
+                guided.extend_from_slice(prefix(connector && i == 0).as_bytes());
+                guided.extend_from_slice(&line[skip + width..]);
+            } else {
+                guided.extend_from_slice(line);
+            }
+        }
+        recycle_buffer(buf);
+        guided
+    }
+
+    /// Returns the indentation unit of the tree levels, if set and not empty
+    fn indent_unit(&self) -> Option<&str> {
+        self.indent_str.as_deref().filter(|unit| !unit.is_empty())
+    }
+
+    /// Returns true if the spans and events are shown as 1 line (oneline or compact mode)
+    fn is_oneline(&self) -> bool {
+        self.oneline || self.compact
+    }
+
+    /// Checks if a field is shown on a span entry (only restricted in the oneline mode)
+    fn shows_span_oneline_field(&self, field: &str) -> bool {
+        match &self.span_oneline_fields {
+            Some(fields) if self.is_oneline() => fields.iter().any(|f| f == field),
+            _ => true,
+        }
+    }
+
+    /// Returns the indentation of the fields, relative to their span or event
+    fn field_offset(&self) -> usize {
+        self.field_indent.unwrap_or(self.indent)
+    }
+
+    /// Writes the kind marker (`S` for spans, `E` for events) if enabled, and returns its width
+    fn write_kind_marker(&self, buf: &mut Vec<u8>, kind: char) -> usize {
+        if !self.kind_marker {
+            return 0;
+        }
+        write!(buf, "{kind} ").unwrap();
+        2
+    }
+
+    /// Returns true if the timestamp is shown
+    fn shows_time(&self) -> bool {
+        self.timestamp_mode != TimestampMode::None
+    }
+
+    /// Returns a timestamp (formatted, relative to the epoch, or elapsed since the start)
+    fn time_str(&self, timestamp: time::OffsetDateTime, uptime: Duration) -> String {
+        if self.timestamp_mode == TimestampMode::Uptime {
+            return format!("+{:.3}s", uptime.as_secs_f64());
+        }
+        match self.time_epoch {
+            Some(epoch) => format!("{:+.6}s", (timestamp - epoch).as_seconds_f64()),
+            None if self.use_local_time => timestamp
+                .to_offset(local_offset())
+                .format(self.time_format)
+                .unwrap_or_else(|_| INVALID_TIME.to_string()),
+            None => timestamp
+                .format(self.time_format)
+                .unwrap_or_else(|_| INVALID_TIME.to_string()),
+        }
+    }
+
+    /// Returns the file info (`file:line`), or `None` if it is not shown
+    fn file_info(&self, file: &str, line: u32) -> Option<String> {
+        if !self.show_file_info {
+            return None;
+        }
+        if file.is_empty() || line == 0 {
+            return match self.missing_file_info {
+                MissingFileInfo::Omit => None,
+                MissingFileInfo::Unknown => Some("<unknown>".to_string()),
+            };
+        }
+        Some(format!("{}:{line}", self.file_path(file)))
+    }
+
+    /// Wraps the file info in a terminal hyperlink (OSC 8) to the absolute path of the file
+    ///
+    /// The file info is unchanged if the hyperlinks or the ANSI escape codes are disabled.
+    fn file_link(&self, file: &str, file_info: String) -> String {
+        if !self.file_hyperlink || !self.ansi || file.is_empty() {
+            return file_info;
+        }
+        let path = Path::new(file);
+        let url = match &self.link_dir {
+            Some(dir) if path.is_relative() => file_url(&dir.join(path)),
+            _ => file_url(path),
+        };
+        format!("\x1b]8;;{url}\x1b\\{file_info}\x1b]8;;\x1b\\")
+    }
+
+    /// Returns a file path, without the file prefix
+    fn file_path<'a>(&self, file: &'a str) -> &'a str {
+        match self
+            .file_prefix
+            .as_deref()
+            .and_then(|prefix| file.strip_prefix(prefix))
+        {
+            Some(path) => path.trim_start_matches(['/', '\\']),
+            None => file,
+        }
+    }
+
+    /// Returns the separator preceding each field (a space in the oneline mode)
+    ///
+    /// With an indentation unit, the field lines repeat the unit of the tree levels.
+    fn field_new_line(&self, tree_level: usize, field_indent: usize) -> String {
+        if self.is_oneline() {
+            return " ".to_string();
+        }
+        match (&self.indent_fn, self.indent_unit()) {
+            (None, Some(unit)) if self.wrapped => {
+                let prefix = self.base_indent + if self.kind_marker { 2 } else { 0 };
+                let rest = field_indent.saturating_sub(prefix + self.tree_indent(tree_level));
+                format!("\n{:prefix$}{}{:rest$}", "", unit.repeat(tree_level), "")
+            }
+            _ => format!("\n{:field_indent$}", ""),
+        }
+    }
+
+    /// Returns a span name within the span name brackets (eg `{name}`)
+    fn bracketed_name(&self, name: &str) -> String {
+        let (open, close) = &self.span_name_brackets;
+        format!("{open}{name}{close}")
+    }
+
+    /// Checks if a field value is hidden (unit, `None` or empty values, if not shown)
+    fn hides_value(&self, value: &str) -> bool {
+        !self.show_unit_fields && matches!(value, "()" | "None" | "\"\"" | "")
+    }
+
+    /// Truncates a field value to the max number of characters, if set
+    fn truncate_value<'a>(&self, value: &'a str) -> Cow<'a, str> {
+        match self.field_value_max_len {
+            Some(max_len) if value.chars().count() > max_len => {
+                let end = value
+                    .char_indices()
+                    .nth(max_len)
+                    .map_or(value.len(), |(i, _)| i);
+                Cow::Owned(format!("{}…", &value[..end]))
+            }
+            _ => Cow::Borrowed(value),
+        }
+    }
+
+    /// Writes a field (`key=value`), preceded by the field separator
+    ///
+    /// Multi-line values are rendered according to the multi-line style (escaped in the oneline mode).
+    fn write_field(&self, buf: &mut Vec<u8>, field_new_line: &str, key: &str, value: &str) {
+        self.write_colored_field(buf, field_new_line, key, value, None);
+    }
+
+    /// Returns true if the escape codes are emitted (ANSI enabled, and colors not disabled)
+    ///
+    /// Without colors, the lines are written directly (without the intermediate colored strings).
+    fn colorizes(&self) -> bool {
+        self.ansi && colored::control::SHOULD_COLORIZE.should_colorize()
+    }
+
+    /// Applies a style to a text, if the escape codes are emitted
+    fn style(&self, text: &str, style: impl FnOnce(&str) -> ColoredString) -> String {
+        styled(text, self.colorizes(), style)
+    }
+
+    /// Writes a metadata line (`label: value`, dimmed), preceded by the field separator
+    fn write_meta(
+        &self,
+        buf: &mut Vec<u8>,
+        field_new_line: &str,
+        label: &str,
+        value: impl std::fmt::Display,
+    ) {
+        if self.colorizes() {
+            let line = format!("{}: {}", label.italic(), value);
+            write!(buf, "{field_new_line}{}", line.dimmed()).unwrap();
+        } else {
+            write!(buf, "{field_new_line}{label}: {value}").unwrap();
+        }
+    }
+
+    /// Writes a field (`key=value`), with an optional value color
+    ///
+    /// The value is truncated before being colored.
+    fn write_colored_field(
+        &self,
+        buf: &mut Vec<u8>,
+        field_new_line: &str,
+        key: &str,
+        value: &str,
+        color: Option<Color>,
+    ) {
+        if self.hides_value(value) {
+            return;
+        }
+        let value = self.truncate_value(value);
+        let colorizes = self.colorizes();
+        let value = match color {
+            Some(color) if colorizes => Cow::Owned(value.color(color).to_string()),
+            _ => value,
+        };
+        if colorizes {
+            let key = format!("{}{}", self.field_prefix, key);
+            write!(buf, "{field_new_line}{}=", key.italic()).unwrap();
+        } else {
+            write!(buf, "{field_new_line}{}{key}=", self.field_prefix).unwrap();
+        }
+        if !value.contains('\n') {
+            write!(buf, "{value}").unwrap();
+            return;
+        }
+
+        let style = if self.is_oneline() {
+            MultilineStyle::Escape
+        } else {
+            self.multiline_field_style
+        };
+        match style {
+            MultilineStyle::Escape => {
+                write!(buf, "{}", value.replace('\n', "\\n")).unwrap();
+            }
+            MultilineStyle::Indent => {
+                let value_new_line = format!("{field_new_line}  ");
+                write!(buf, "{}", value.replace('\n', &value_new_line)).unwrap();
+            }
+            MultilineStyle::Fence => {
+                write!(buf, "{field_new_line}```").unwrap();
+                for line in value.lines() {
+                    write!(buf, "{field_new_line}{line}").unwrap();
+                }
+                write!(buf, "{field_new_line}```").unwrap();
+            }
+        }
+    }
+
+    /// Writes the indentation of a line, including the base indentation, and returns its width
+    ///
+    /// The tree indentation only applies to the wrapped mode.
+    fn write_line_indent(&self, buf: &mut Vec<u8>, tree_level: usize) -> usize {
+        if !self.wrapped {
+            write!(buf, "{:w$}", "", w = self.base_indent).unwrap();
+            return self.base_indent;
+        }
+        let tree_indent = self.tree_indent(tree_level);
+        match (&self.indent_fn, self.indent_unit()) {
+            (None, Some(unit)) => write!(
+                buf,
+                "{:w$}{}",
+                "",
+                unit.repeat(tree_level),
+                w = self.base_indent
+            )
+            .unwrap(),
+            _ => write!(buf, "{:w$}", "", w = self.base_indent + tree_indent).unwrap(),
+        }
+        self.base_indent + tree_indent
+    }
+}
+
+impl PrettyConsoleLayer {
+    /// Creates a layer with the default options (same as [Default::default])
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a layer with formatting options
+    ///
+    /// The builder methods can still be used to change the options.
+    pub fn with_options(mut options: PrettyFormatOptions) -> Self {
+        let mut layer = Self::default();
+        layer.state.ansi.set(options.ansi);
+        options.link_dir = layer.format.link_dir.take();
+        Self {
+            format: options,
+            ..layer
+        }
+    }
+
+    /// Returns the formatting options
+    pub fn options(&self) -> &PrettyFormatOptions {
+        &self.format
+    }
+
+    /// Creates a layer printing each span and event as 1 line, without the target and file info
+    ///
+    /// See [compact](Self::compact) for an even shorter event format.
+    pub fn terse() -> Self {
+        Self::default()
+            .oneline(true)
+            .show_target(false)
+            .show_file_info(false)
+    }
+
+    /// Creates a layer printing the span trees (wrapped mode) with all the metadata
+    ///
+    /// The time, target, file info, span info, thread, and the busy and idle time of the spans are shown.
+    pub fn verbose() -> Self {
+        Self::default()
+            .wrapped(true)
+            .show_time(true)
+            .show_target(true)
+            .show_file_info(true)
+            .show_span_info(true)
+            .show_thread(true)
+            .show_busy_idle(true)
+    }
+
+    /// Sets the kind is wrapped
+    pub fn wrapped(mut self, wrapped: bool) -> Self {
+        self.format.wrapped = wrapped;
+        self
+    }
+
+    /// Shows each span and event as 1 line
+    pub fn oneline(mut self, oneline: bool) -> Self {
+        self.format.oneline = oneline;
+        self
+    }
+
+    /// Shows each event as `LEVEL message key=value ...`, without the metadata labels
+    ///
+    /// The compact mode overrides the oneline mode for events, and spans are shown as 1 line.
+    pub fn compact(mut self, compact: bool) -> Self {
+        self.format.compact = compact;
+        self
+    }
+
+    /// Sets the time format
+    pub fn time_format(
+        mut self,
+        format: &'static [time::format_description::FormatItem<'static>],
+    ) -> Self {
+        self.format.time_format = format;
+        self
+    }
+
+    /// Sets an epoch, so that the time is shown as an offset from it (eg `+12.345678s`)
+    ///
+    /// Services sharing the same epoch get aligned relative timelines. Times before the epoch
+    /// are negative.
+    pub fn time_epoch(mut self, epoch: time::OffsetDateTime) -> Self {
+        self.format.time_epoch = Some(epoch);
+        self
+    }
+
+    /// Sets if only the events are shown
+    pub fn events_only(mut self, show: bool) -> Self {
+        self.format.events_only = show;
+        self
+    }
+
+    /// Sets if the time is shown
+    ///
+    /// Hiding the time is the same as [TimestampMode::None], and showing it keeps the uptime mode.
+    pub fn show_time(mut self, show: bool) -> Self {
+        if !show {
+            self.format.timestamp_mode = TimestampMode::None;
+        } else if self.format.timestamp_mode == TimestampMode::None {
+            self.format.timestamp_mode = TimestampMode::WallClock;
+        }
+        self
+    }
+
+    /// Sets the timestamp mode (wall clock, uptime, or none)
+    pub fn timestamp_mode(mut self, mode: TimestampMode) -> Self {
+        self.format.timestamp_mode = mode;
+        self
+    }
+
+    /// Sets if the target is shown
+    pub fn show_target(mut self, show: bool) -> Self {
+        self.format.show_target = show;
+        self
+    }
+
+    /// Sets if the file info is shown
+    pub fn show_file_info(mut self, show: bool) -> Self {
+        self.format.show_file_info = show;
+        self
+    }
+
+    /// Sets if the span inline info is shown
+    pub fn show_span_info(mut self, show: bool) -> Self {
+        self.format.show_span_info = show;
+        self
+    }
+
+    /// Sets the indentation (in x spaces)
+    pub fn indent(mut self, indent: usize) -> Self {
+        self.format.indent = indent;
+        self.format.indent_str = None;
+        self
+    }
+
+    /// Sets the indentation unit of each tree level (eg `"\t"` or `"│  "`), repeated per level
+    ///
+    /// An empty unit is ignored (the tree is indented with spaces).
+    pub fn indent_str(mut self, unit: impl Into<String>) -> Self {
+        self.format.indent_str = Some(unit.into());
+        self
+    }
+
+    /// Sets the indentation (in x spaces) of each tree level
+    ///
+    /// The function receives the tree level (starting at 0) and returns the indentation added by that level,
+    /// which allows deep trees to shrink their indentation.
+    pub fn indent_fn(mut self, indent_fn: impl Fn(usize) -> usize + Send + Sync + 'static) -> Self {
+        self.format.indent_fn = Some(Callback(Arc::new(indent_fn)));
+        self
+    }
+
+    /// Sets the name of the event field holding the message (default: `message`)
+    pub fn message_field(mut self, field: &'static str) -> Self {
+        self.format.message_field = field;
+        self
+    }
+
+    /// Sets if events are printed as RFC 5424 syslog lines
+    ///
+    /// In this mode, spans are not printed, and the event fields are set as structured data.
+    pub fn syslog(mut self, syslog: bool) -> Self {
+        self.format.output_format = if syslog {
+            OutputFormat::Syslog
+        } else {
+            OutputFormat::Pretty
+        };
+        self
+    }
+
+    /// Sets if events are printed as tab-separated values
+    ///
+    /// A header row is printed first, then one row per event with the columns
+    /// `timestamp`, `level`, `target`, `message`, `span` and `fields`.
+    /// Spans are not printed, and the event fields are collapsed into the last column.
+    /// Tabs and line breaks in values are escaped.
+    pub fn tsv(mut self, tsv: bool) -> Self {
+        self.format.output_format = if tsv {
+            OutputFormat::Tsv
+        } else {
+            OutputFormat::Pretty
+        };
+        self
+    }
+
+    /// Sets if events are printed as logfmt lines (eg `ts=... level=info msg="..." target=... key=value`)
+    ///
+    /// Values which are empty or contain spaces, `=` or quotes are quoted, with the quotes escaped.
+    /// Spans are not printed, and the event span name is set as `span`.
+    pub fn logfmt(mut self, logfmt: bool) -> Self {
+        self.format.output_format = if logfmt {
+            OutputFormat::Logfmt
+        } else {
+            OutputFormat::Pretty
+        };
+        self
+    }
+
+    /// Sets if spans and events are printed as trace events, for chrome://tracing or Perfetto
+    ///
+    /// Span entries and exits are `B` and `E` events, and events are instant (`i`) events. The
+    /// timestamps (`ts`) are in microseconds since the first trace event. The output is a JSON array
+    /// (1 object per line) which is never closed, as allowed by the Trace Event Format.
+    #[cfg(feature = "serde")]
+    pub fn chrome_trace(mut self, chrome_trace: bool) -> Self {
+        self.format.output_format = if chrome_trace {
+            OutputFormat::ChromeTrace
+        } else {
+            OutputFormat::Pretty
+        };
+        self
+    }
+
+    /// Sets if events are printed as JSON lines (1 object per line)
+    ///
+    /// The keys are `timestamp`, `level`, `target`, `file`, `line`, `message`, `span` and `fields`.
+    /// The `span` object holds the `id`, `name` and `fields` (attributes) of the event span, or is
+    /// `null` for an event outside of a span. Spans are not printed.
+    #[cfg(feature = "serde")]
+    pub fn json(mut self, json: bool) -> Self {
+        self.format.output_format = if json {
+            OutputFormat::Json
+        } else {
+            OutputFormat::Pretty
+        };
+        self
+    }
+
+    /// Sets if the span trees are printed as YAML documents
+    ///
+    /// This implies the wrapped mode: each root span is printed when closed, with its attributes,
+    /// duration, events and nested children. Events outside of a span are printed as `event` documents.
+    #[cfg(feature = "serde")]
+    pub fn yaml(mut self, yaml: bool) -> Self {
+        if yaml {
+            self.format.output_format = OutputFormat::Yaml;
+            self.format.wrapped = true;
+        } else {
+            self.format.output_format = OutputFormat::Pretty;
+        }
+        self
+    }
+
+    /// Sets the syslog facility code (0-23, default: 1 for user-level messages)
+    pub fn syslog_facility(mut self, facility: u8) -> Self {
+        assert!(facility < 24, "invalid syslog facility");
+        self.format.syslog_facility = facility;
+        self
+    }
+
+    /// Sets the syslog app name (default: executable name)
+    pub fn syslog_app_name(mut self, app_name: impl Into<String>) -> Self {
+        self.format.syslog_app_name = Some(app_name.into());
+        self
+    }
+
+    /// Sets the position of the child spans relative to the parent events (wrapped mode)
+    pub fn children_position(mut self, position: ChildrenPosition) -> Self {
+        self.format.children_position = position;
+        self
+    }
+
+    /// Sets the style of the span breadcrumb printed before event messages
+    pub fn breadcrumb_style(mut self, style: BreadcrumbStyle) -> Self {
+        self.format.breadcrumb_style = style;
+        self
+    }
+
+    /// Sets the fraction of spans which are printed (0.0 to 1.0, default: 1.0)
+    ///
+    /// Spans are sampled deterministically (eg 0.25 prints 1 span out of 4). The entry and exit lines of
+    /// the other spans are not printed, but their events are. The number of skipped spans is printed
+    /// before the next sampled span, and the remaining number when the
+    /// [summary guard](Self::summary_guard) is dropped.
+    pub fn span_sample_rate(mut self, rate: f64) -> Self {
+        self.format.span_sample_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Sets if a legend explaining the format is printed before the first record
+    pub fn legend(mut self, legend: bool) -> Self {
+        self.format.legend = legend;
+        self
+    }
+
+    /// Sets if the span exit also shows the duration as a machine-parseable `duration_ns` field
+    pub fn machine_duration(mut self, show: bool) -> Self {
+        self.format.machine_duration = show;
+        self
+    }
+
+    /// Sets the default output stream (default: `stderr`)
+    ///
+    /// The stream is used for spans and events alike, unless a dedicated writer is set.
+    pub fn with_output(self, stream: OutputStream) -> Self {
+        let mut writers = self.state.writers.lock().unwrap();
+        writers.stream = stream;
+        writers.make_writer = None;
+        drop(writers);
+        self
+    }
+
+    /// Sets the default writer, replacing the output stream
+    ///
+    /// A writer is acquired for each record (1 or more lines), and released once the record is written.
+    ///
+    /// ```
+    /// use std::io;
+    /// use tracing_ext::sub::PrettyConsoleLayer;
+    ///
+    /// let layer = PrettyConsoleLayer::default().with_writer(io::stdout);
+    /// ```
+    pub fn with_writer<W>(self, make_writer: W) -> Self
+    where
+        W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        self.state.writers.lock().unwrap().make_writer = Some(BoxMakeWriter::new(make_writer));
+        self
+    }
+
+    /// Sets if the output lines are numbered (right-aligned, in the left margin)
+    ///
+    /// The numbering covers all the lines written by the layer (spans, events and summaries), in
+    /// both wrapped and streaming modes. It starts at 1 for each layer, and is never reset.
+    pub fn line_numbers(self, enabled: bool) -> Self {
+        self.state.writers.lock().unwrap().line_number = enabled.then_some(0);
+        self
+    }
+
+    /// Routes the events whose target starts with a prefix to a dedicated writer
+    ///
+    /// If several prefixes match, the longest one is used. Other events use the default writer.
+    pub fn target_route(
+        self,
+        prefix: impl Into<String>,
+        writer: impl Write + Send + 'static,
+    ) -> Self {
+        self.state
+            .writers
+            .lock()
+            .unwrap()
+            .routes
+            .push((prefix.into(), Box::new(writer)));
+        self
+    }
+
+    /// Sets the indentation (in x spaces) added to every line, to shift the whole output right
+    pub fn base_indent(mut self, indent: usize) -> Self {
+        self.format.base_indent = indent;
+        self
+    }
+
+    /// Sets a prefix for the span attributes and event fields names (eg `app.`)
+    ///
+    /// The message and the metadata fields (time, target, etc.) are not prefixed.
+    pub fn field_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.format.field_prefix = prefix.into();
+        self
+    }
+
+    /// Sets if span attributes with the same key and value as in the parent span are hidden (wrapped mode)
+    pub fn hide_redundant_attrs(mut self, hide: bool) -> Self {
+        self.format.hide_redundant_attrs = hide;
+        self
+    }
+
+    /// Sets icons shown in a column before the event level, by target prefix
+    ///
+    /// If several prefixes match, the longest one is used. Events with an unmatched target have a blank icon.
+    pub fn target_icons(mut self, icons: HashMap<String, char>) -> Self {
+        self.format.target_icons = icons;
+        self
+    }
+
+    /// Sets when the writers are flushed (default: after each record)
+    ///
+    /// Batching only makes a difference with buffered writers (eg [std::io::BufWriter]): the lines
+    /// are written to the underlying writer when a batch is flushed (or when the buffer is full),
+    /// instead of once per line.
+    pub fn flush_policy(mut self, policy: FlushPolicy) -> Self {
+        self.format.flush_policy = policy;
+        self
+    }
+
+    /// Sets if span trees without WARN or ERROR events are collapsed into a single summary line (wrapped mode)
+    ///
+    /// Span trees containing warnings or errors are fully expanded.
+    pub fn collapse_successful(mut self, collapse: bool) -> Self {
+        self.format.collapse_successful = collapse;
+        self
+    }
+
+    /// Sets the symbols of the span entry and exit (wrapped mode), eg `▶` and `◀`
+    ///
+    /// The symbols replace the braces: `▶ name` and `◀ name` instead of `{name}` and `!{name}`.
+    pub fn span_symbols(mut self, enter: impl Into<String>, exit: impl Into<String>) -> Self {
+        self.format.span_symbols = Some((enter.into(), exit.into()));
+        self
+    }
+
+    /// Sets if the span symbols are colored, green for the entry and blue for the exit (wrapped mode)
+    pub fn color_span_symbols(mut self, color: bool) -> Self {
+        self.format.color_span_symbols = color;
+        self
+    }
+
+    /// Adds a sink, which receives all the records (spans and events) in addition to the default output
+    ///
+    /// If `ansi` is false, the ANSI escape codes (colors) are stripped, which allows for instance a colored
+    /// terminal and a plain log file. Records are serialized once: stripping costs an extra pass
+    /// (and copy) over each record, shared by all the sinks without ANSI codes.
+    pub fn sink(self, writer: impl Write + Send + 'static, ansi: bool) -> Self {
+        self.handle().add_sink(writer, ansi);
+        self
+    }
+
+    /// Sets a provider of context fields, called for each event (eg to read a task-local context)
+    ///
+    /// Context fields are printed after the event fields. If an event field has the same name as a
+    /// context field, the event field wins and the context field is dropped.
+    ///
+    /// ```
+    /// use tracing_ext::sub::PrettyConsoleLayer;
+    ///
+    /// tokio::task_local! {
+    ///     static REQUEST_ID: String;
+    /// }
+    ///
+    /// let layer = PrettyConsoleLayer::default().context_fields(|| {
+    ///     REQUEST_ID
+    ///         .try_with(|id| vec![("request_id".to_string(), id.clone())])
+    ///         .unwrap_or_default()
+    /// });
+    /// ```
+    pub fn context_fields(
+        mut self,
+        provider: impl Fn() -> Vec<(String, String)> + Send + Sync + 'static,
+    ) -> Self {
+        self.format.context_fields = Some(Callback(Arc::new(provider)));
+        self
+    }
+
+    /// Sets if the span exit shows a bar with the span duration relative to its parent (wrapped mode)
+    ///
+    /// The root span bar is relative to its own duration.
+    pub fn duration_bars(mut self, show: bool) -> Self {
+        self.format.duration_bars = show;
+        self
+    }
+
+    /// Sets the width of the duration bars (in characters, default: 10)
+    pub fn duration_bar_width(mut self, width: usize) -> Self {
+        self.format.duration_bar_width = width;
+        self
+    }
+
+    /// Sets if the event target is hidden when it is the same as the target of its span
+    pub fn hide_redundant_target(mut self, hide: bool) -> Self {
+        self.format.hide_redundant_target = hide;
+        self
+    }
+
+    /// Sets if the span entry shows the delay between its creation and its first enter
+    ///
+    /// For async tasks, this is typically the scheduling latency.
+    pub fn show_schedule_delay(mut self, show: bool) -> Self {
+        self.format.show_schedule_delay = show;
+        self
+    }
+
+    /// Sets a separator line printed between consecutive root span trees (e.g. `────────`)
+    pub fn root_separator(mut self, separator: Option<String>) -> Self {
+        self.format.root_separator = separator;
+        self
+    }
+
+    /// Sets how field values containing newlines are rendered (default: escaped)
+    pub fn multiline_field_style(mut self, style: MultilineStyle) -> Self {
+        self.format.multiline_field_style = style;
+        self
+    }
+
+    /// Sets if the ERROR events are recapped under an `ERRORS:` header when the summary guard is dropped
+    ///
+    /// See [summary_guard](Self::summary_guard).
+    pub fn error_summary_on_exit(mut self, enabled: bool) -> Self {
+        self.format.error_summary_on_exit = enabled;
+        self
+    }
+
+    /// Sets if the event counts are summarized by target (as a tree) when the summary guard is dropped
+    ///
+    /// See [summary_guard](Self::summary_guard).
+    pub fn target_summary_on_exit(mut self, enabled: bool) -> Self {
+        self.format.target_summary_on_exit = enabled;
+        self
+    }
+
+    /// Sets the minimum level of the events within a span, given the span attributes
+    ///
+    /// The events of the current span which are more verbose than the returned level are not printed.
+    /// Events outside of a span are not affected.
+    ///
+    /// ```
+    /// use tracing::Level;
+    /// use tracing_ext::sub::PrettyConsoleLayer;
+    ///
+    /// let layer = PrettyConsoleLayer::default().dynamic_span_event_level(|attrs| {
+    ///     match attrs.get("verbose").as_deref() {
+    ///         Some("true") => Level::TRACE,
+    ///         _ => Level::WARN,
+    ///     }
+    /// });
+    /// ```
+    pub fn dynamic_span_event_level(
+        mut self,
+        level_fn: impl Fn(&Fields) -> Level + Send + Sync + 'static,
+    ) -> Self {
+        self.format.span_event_level = Some(Callback(Arc::new(level_fn)));
+        self
+    }
+
+    /// Sets if each record starts with a kind marker (`S` for spans, `E` for events)
+    ///
+    /// In the TSV mode, the marker is set in a leading `kind` column.
+    pub fn kind_marker(mut self, marker: bool) -> Self {
+        self.format.kind_marker = marker;
+        self
+    }
+
+    /// Sets if the fields with a unit (`()`), `None` or empty value are shown (default: true)
+    pub fn show_unit_fields(mut self, show: bool) -> Self {
+        self.format.show_unit_fields = show;
+        self
+    }
+
+    /// Sets if the first line of an event combines the time, level, span and message
+    ///
+    /// eg `14:03:01.123456 INFO  [span] message`, followed by the target, file info and fields.
+    pub fn header_line(mut self, header_line: bool) -> Self {
+        self.format.header_line = header_line;
+        self
+    }
+
+    /// Sets if the events are printed by another layer, so that this layer only prints the spans
+    ///
+    /// This is the recommended composition with the `fmt` layer of `tracing-subscriber`: the events are
+    /// printed once by the `fmt` layer, and the span entries and exits by this layer.
+    /// Span events of the `fmt` layer should remain disabled (the default).
+    ///
+    /// ```
+    /// use tracing_ext::sub::PrettyConsoleLayer;
+    /// use tracing_subscriber::prelude::*;
+    ///
+    /// tracing_subscriber::registry()
+    ///     .with(tracing_subscriber::fmt::layer())
+    ///     .with(PrettyConsoleLayer::default().events_from_other_layer(true))
+    ///     .init();
+    /// ```
+    pub fn events_from_other_layer(mut self, enabled: bool) -> Self {
+        self.format.events_from_other_layer = enabled;
+        self
+    }
+
+    /// Sets the color of the numeric event field values, given their value (default: no color)
+    ///
+    /// ```
+    /// use colored::Color;
+    /// use tracing_ext::sub::PrettyConsoleLayer;
+    ///
+    /// let layer = PrettyConsoleLayer::default().numeric_value_style(|value| match value {
+    ///     v if v < 0.0 => Some(Color::Red),
+    ///     v if v >= 1000.0 => Some(Color::BrightYellow),
+    ///     _ => None,
+    /// });
+    /// ```
+    pub fn numeric_value_style(
+        mut self,
+        style: impl Fn(f64) -> Option<Color> + Send + Sync + 'static,
+    ) -> Self {
+        self.format.numeric_value_style = Some(Callback(Arc::new(style)));
+        self
+    }
+
+    /// Sets if consecutive root trees with the same structure are collapsed (wrapped mode)
+    ///
+    /// Two trees have the same structure if they have the same span names, nesting, and event levels and
+    /// messages. Attributes, fields, timings and IDs are ignored. The first tree is printed, and its
+    /// repeats are collapsed into a `(xN) {root}` line, printed when a different tree is closed (or
+    /// when the [summary guard](Self::summary_guard) is dropped).
+    pub fn dedupe_trees(mut self, dedupe: bool) -> Self {
+        self.format.dedupe_trees = dedupe;
+        self
+    }
+
+    /// Sets where the span attributes are printed (default: on the span entry)
+    ///
+    /// On the span exit, the attributes have their final values, including the values recorded after
+    /// the span creation (eg with `Span::record`).
+    pub fn attr_placement(mut self, placement: AttrPlacement) -> Self {
+        self.format.attr_placement = placement;
+        self
+    }
+
+    /// Sets the indentation of the fields, independently of the nesting indentation (default: same)
+    pub fn field_indent(mut self, indent: usize) -> Self {
+        self.format.field_indent = Some(indent);
+        self
+    }
+
+    /// Sets if the spans which are created but never entered are hidden
+    ///
+    /// Otherwise, they are printed with a `(never entered)` note (when closed, in the streaming mode).
+    /// In the wrapped mode, the events and children of a hidden span are printed one level up.
+    pub fn hide_unentered_spans(mut self, hide: bool) -> Self {
+        self.format.hide_unentered_spans = hide;
+        self
+    }
+
+    /// Sets the duration budget of the spans, given their name
+    ///
+    /// The exit of a span with a budget shows its duration relative to the budget
+    /// (eg `45.00ms/100.00ms (45%)`, or `45000us/100000us (45%)` with
+    /// [duration_human(false)](Self::duration_human)), in red if over budget, and in green otherwise.
+    /// With a zero budget, the percentage is not shown.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use tracing_ext::sub::PrettyConsoleLayer;
+    ///
+    /// let layer = PrettyConsoleLayer::default().span_budget(|name| match name {
+    ///     "request" => Some(Duration::from_millis(100)),
+    ///     _ => None,
+    /// });
+    /// ```
+    pub fn span_budget(
+        mut self,
+        budget: impl Fn(&str) -> Option<Duration> + Send + Sync + 'static,
+    ) -> Self {
+        self.format.span_budget = Some(Callback(Arc::new(budget)));
+        self
+    }
+
+    /// Sets the build info
+    ///
+    /// It is printed in a startup banner before the first record, and added to each record in the
+    /// structured formats (syslog, TSV and YAML).
+    pub fn build_info(mut self, build_info: BuildInfo) -> Self {
+        self.format.build_info = Some(build_info);
+        self
+    }
+
+    /// Sets the fields shown on the span entries, in the oneline mode (default: all the enabled fields)
+    ///
+    /// The fields are `time`, `scheduled`, `span.id`, `parents`, `target`, `file`, and the span attribute names.
+    ///
+    /// ```
+    /// use tracing_ext::sub::PrettyConsoleLayer;
+    ///
+    /// let layer = PrettyConsoleLayer::default()
+    ///     .oneline(true)
+    ///     .span_oneline_fields(&["span.id"]);
+    /// ```
+    pub fn span_oneline_fields(mut self, fields: &[&str]) -> Self {
+        self.format.span_oneline_fields = Some(fields.iter().map(|f| f.to_string()).collect());
+        self
+    }
+
+    /// Sets if the events of a span are grouped by level, most severe first (wrapped mode)
+    ///
+    /// Within a level, the events are in chronological order. This does not apply when the children
+    /// are interleaved with the events (see [ChildrenPosition::Interleaved]).
+    pub fn group_events_by_level(mut self, group: bool) -> Self {
+        self.format.group_events_by_level = group;
+        self
+    }
+
+    /// Sets the rendering of the file info when the metadata has no file
+    pub fn missing_file_info(mut self, missing: MissingFileInfo) -> Self {
+        self.format.missing_file_info = missing;
+        self
+    }
+
+    /// Sets if spans without events nor children are printed on a single line (wrapped mode)
+    ///
+    /// The entry and exit are combined (eg `{name}..!{name} 3us`), followed by the span fields.
+    pub fn collapse_empty_spans(mut self, collapse: bool) -> Self {
+        self.format.collapse_empty_spans = collapse;
+        self
+    }
+
+    /// Sets a field overriding the displayed level of the events (eg `severity = "critical"`)
+    ///
+    /// The field values are mapped to levels (case insensitive). A mapped event is labelled with the
+    /// field value, colored as the mapped level. Unmapped values keep the event level.
+    ///
+    /// ```
+    /// use tracing::Level;
+    /// use tracing_ext::sub::PrettyConsoleLayer;
+    ///
+    /// let layer = PrettyConsoleLayer::default()
+    ///     .severity_field("severity", [("critical", Level::ERROR), ("notice", Level::INFO)]);
+    /// ```
+    pub fn severity_field<'a>(
+        mut self,
+        field: &str,
+        mapping: impl IntoIterator<Item = (&'a str, Level)>,
+    ) -> Self {
+        let mapping = mapping
+            .into_iter()
+            .map(|(value, level)| (value.to_lowercase(), level))
+            .collect();
+        self.format.severity_field = Some((field.to_string(), mapping));
+        self
+    }
+
+    /// Sets if ANSI escape codes (colors and styles) are emitted
+    ///
+    /// By default, the escape codes are emitted unless the `NO_COLOR` environment variable is set
+    /// (checked when the layer is built). An explicit call overrides `NO_COLOR`.
+    ///
+    /// When disabled, the escape codes are stripped from every record, for all the writers and sinks.
+    pub fn with_ansi(mut self, ansi: bool) -> Self {
+        self.format.ansi = ansi;
+        self.state.ansi.set(ansi);
+        self
+    }
+
+    /// Sets if the IDs of the span ancestors are shown on the span entry (eg `parents: [1, 3]`)
+    ///
+    /// This allows to rebuild the span tree from the streamed output. The field is omitted for root spans.
+    pub fn show_ancestor_ids(mut self, show: bool) -> Self {
+        self.format.show_ancestor_ids = show;
+        self
+    }
+
+    /// Sets the order of the metadata fields, on the span entries and events (pretty format)
+    ///
+    /// Fields which are not listed are not shown. The fields must still be enabled by their own
+    /// option (eg [show_target](Self::show_target)).
+    ///
+    /// # Panics
+    ///
+    /// Panics if a field is listed more than once.
+    pub fn metadata_order(mut self, order: Vec<MetaField>) -> Self {
+        for (i, field) in order.iter().enumerate() {
+            assert!(
+                !order[..i].contains(field),
+                "duplicate metadata field: {field:?}"
+            );
+        }
+        self.format.metadata_order = order;
+        self
+    }
+
+    /// Sets if the ID of the tokio task is shown on the span entries and events
+    ///
+    /// The task IDs are the ones shown by `tokio-console`. Spans and events created outside of a
+    /// task have no task ID.
+    #[cfg(feature = "tokio")]
+    pub fn show_task_id(mut self, show: bool) -> Self {
+        self.format.show_task_id = show;
+        self
+    }
+
+    /// Sets if the timestamps are shown in local time (instead of UTC)
+    ///
+    /// The local offset is determined once, when this method is called. It cannot be determined
+    /// safely once the program has spawned threads on some platforms (eg Linux): the timestamps
+    /// then fall back to UTC. The layer should be built at the start of `main` for the local time.
+    pub fn use_local_time(mut self, enabled: bool) -> Self {
+        if enabled {
+            local_offset();
+        }
+        self.format.use_local_time = enabled;
+        self
+    }
+
+    /// Sets a predicate on the events (message and fields), to focus on the matching events (wrapped mode)
+    ///
+    /// Only the spans containing a matching event (in their subtree) are shown, with all their
+    /// events, and their ancestors for context. Other spans, and unmatched events outside of a span,
+    /// are not shown.
+    ///
+    /// ```
+    /// use tracing_ext::sub::PrettyConsoleLayer;
+    ///
+    /// let layer = PrettyConsoleLayer::default()
+    ///     .wrapped(true)
+    ///     .focus(|message, fields| message.contains("timeout") || fields.get("order_id").is_some());
+    /// ```
+    pub fn focus(mut self, focus: impl Fn(&str, &Fields) -> bool + Send + Sync + 'static) -> Self {
+        self.format.focus = Some(Callback(Arc::new(focus)));
+        self
+    }
+
+    /// Sets if the span durations are shown in a unit depending on their magnitude (default: true)
+    ///
+    /// The unit is `us` under 1ms, `ms` under 1s, otherwise `s` (eg `500us`, `5.20ms`, `2.00s`).
+    /// When disabled, the durations are always in microseconds (eg `2000000us`), for parsing.
+    pub fn duration_human(mut self, human: bool) -> Self {
+        self.format.duration_human = human;
+        self
+    }
+
+    /// Sets the most verbose level printed (eg `Level::WARN` prints the WARN and ERROR records)
+    ///
+    /// Spans and events above this level are skipped by this layer only: other layers still receive
+    /// them. The events of a skipped span are still printed if they are within the level.
+    pub fn max_level(mut self, level: Level) -> Self {
+        self.format.max_level = Some(level);
+        self
+    }
+
+    /// Sets the target prefixes of the printed spans and events (eg `myapp::`)
+    ///
+    /// Records with another target are skipped by this layer only, like with [max_level](Self::max_level).
+    pub fn with_target_filter(mut self, prefixes: Vec<String>) -> Self {
+        self.format.target_allowlist = Some(prefixes);
+        self
+    }
+
+    /// Sets the target prefixes of the spans and events which are not printed (eg `hyper::`)
+    ///
+    /// The exclusion applies after the [with_target_filter](Self::with_target_filter) prefixes.
+    pub fn with_target_exclude(mut self, prefixes: Vec<String>) -> Self {
+        self.format.target_denylist = prefixes;
+        self
+    }
+
+    /// Sets if the name and ID of the thread which emitted an event are shown
+    pub fn show_thread(mut self, show: bool) -> Self {
+        self.format.show_thread = show;
+        self
+    }
+
+    /// Sets the max number of characters of a field value (event fields and span attributes)
+    ///
+    /// Longer values are truncated and end with `…`.
+    pub fn field_value_max_len(mut self, max_len: Option<usize>) -> Self {
+        self.format.field_value_max_len = max_len;
+        self
+    }
+
+    /// Sets the names of the fields whose values are replaced with `***` (eg `password`)
+    ///
+    /// The names match exactly, and apply to the event fields, the context fields, and the span attributes.
+    pub fn redact_fields(mut self, names: Vec<&'static str>) -> Self {
+        self.format.redacted_fields = names;
+        self
+    }
+
+    /// Sets if the attributes of the event span and its ancestors are shown on the event (eg `request_id`)
+    ///
+    /// The attributes are shown after the event fields. On a name clash, the attribute of the innermost
+    /// span is kept, and an event field overrides a span attribute.
+    pub fn inherit_span_fields(mut self, inherit: bool) -> Self {
+        self.format.inherit_span_fields = inherit;
+        self
+    }
+
+    /// Sets if the busy time (while entered) and idle time of the spans are shown on the span exit
+    ///
+    /// An async span is entered on each poll, so its busy time is the time spent polling it, and its
+    /// idle time is the time spent waiting (eg on a timer or IO).
+    pub fn show_busy_idle(mut self, show: bool) -> Self {
+        self.format.show_busy_idle = show;
+        self
+    }
+
+    /// Sets the marker of the span entries, in the streaming mode (default: `-->`)
+    pub fn span_enter_marker(mut self, marker: impl Into<String>) -> Self {
+        self.format.span_enter_marker = marker.into();
+        self
+    }
+
+    /// Sets the marker of the span exits, in the streaming mode (default: `<--`)
+    pub fn span_exit_marker(mut self, marker: impl Into<String>) -> Self {
+        self.format.span_exit_marker = marker.into();
+        self
+    }
+
+    /// Sets the brackets around the span names (default: `{` and `}`)
+    ///
+    /// The span exits are prefixed with `!` (eg `!{name}`).
+    pub fn span_name_brackets(mut self, brackets: (String, String)) -> Self {
+        self.format.span_name_brackets = brackets;
+        self
+    }
+
+    /// Sets the label of a level (eg `info`)
+    ///
+    /// Labels are padded to 5 characters, so longer labels shift the event messages.
+    pub fn level_label(mut self, level: Level, label: &str) -> Self {
+        self.format.level_style.set_label(level, label);
+        self
+    }
+
+    /// Sets the color of a level
+    pub fn level_color(mut self, level: Level, color: Color) -> Self {
+        self.format.level_style.set_color(level, color);
+        self
+    }
 
-/// Formatting options (for spans and events)
-#[derive(Debug)]
-struct PrettyFormatOptions {
-    /// Defines if the display is wrapped
-    pub wrapped: bool,
-    /// If true, spans and events are printed in 1 line
-    pub oneline: bool,
-    /// Time format
-    pub time_format: &'static [time::format_description::FormatItem<'static>],
-    /// The span is shown (enter and exit info)
-    pub events_only: bool,
-    /// The timestanp is shown
-    pub show_time: bool,
-    /// The target is shown
-    pub show_target: bool,
-    /// The file info is shown
-    pub show_file_info: bool,
-    /// Shows the span info
-    pub show_span_info: bool,
-    /// Indentation (x spaces) - invalid if the `oneline` option is set
-    pub indent: usize,
-}
+    /// Sets a prefix stripped from the file paths of the spans and events
+    ///
+    /// Paths are usually relative to the workspace, except for dependencies and some builds
+    /// (eg `/home/me/proj/src/foo.rs`). Use `env!("CARGO_MANIFEST_DIR")` to show them as `src/foo.rs`.
+    pub fn strip_file_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.format.file_prefix = Some(prefix.into());
+        self
+    }
 
-impl Default for PrettyFormatOptions {
-    fn default() -> Self {
-        Self {
-            wrapped: false,
-            oneline: false,
-            time_format: TIME_FORMAT_DEFAULT,
-            events_only: false,
-            show_time: true,
-            show_target: true,
-            show_file_info: true,
-            show_span_info: true,
-            indent: 6,
-        }
+    /// Sets if the file info is a terminal hyperlink (OSC 8) to the source file
+    ///
+    /// Relative paths are resolved from the current directory. Terminals which do not support
+    /// hyperlinks show the file info as plain text.
+    pub fn file_hyperlink(mut self, hyperlink: bool) -> Self {
+        self.format.file_hyperlink = hyperlink;
+        self
     }
-}
 
-impl PrettyConsoleLayer {
-    /// Sets the kind is wrapped
-    pub fn wrapped(mut self, wrapped: bool) -> Self {
-        self.format.wrapped = wrapped;
+    /// Sets if the busy time of the spans is accumulated by span path (eg `root;child`)
+    ///
+    /// The folded stacks are written with [PrettyConsoleHandle::write_folded_stacks] (eg at the end
+    /// of `main`), independently of the printed spans.
+    pub fn folded_stacks(mut self, folded_stacks: bool) -> Self {
+        self.format.folded_stacks = folded_stacks;
         self
     }
 
-    /// Shows each span and event as 1 line
-    pub fn oneline(mut self, oneline: bool) -> Self {
-        self.format.oneline = oneline;
+    /// Sets if the time since the previous event of the same thread is shown (eg `+42ms`)
+    ///
+    /// This is independent of the timestamp, and the first event shows `+0ms`.
+    pub fn show_delta(mut self, show: bool) -> Self {
+        self.format.show_delta = show;
         self
     }
 
-    /// Sets the time format
-    pub fn time_format(
-        mut self,
-        format: &'static [time::format_description::FormatItem<'static>],
-    ) -> Self {
-        self.format.time_format = format;
+    /// Sets if the wrapped tree is drawn with guides (`├─`, `└─`, `│`) in the tree indentation
+    pub fn tree_guides(mut self, show: bool) -> Self {
+        self.format.tree_guides = show;
         self
     }
 
-    /// Sets if only the events are shown
-    pub fn events_only(mut self, show: bool) -> Self {
-        self.format.events_only = show;
+    /// Sets a duration below which the spans are omitted from the tree (wrapped mode)
+    ///
+    /// The events and children of a collapsed span are printed within its parent. Spans with
+    /// events at the keep level (WARN by default) or more severe are not collapsed, and neither
+    /// is the root span.
+    pub fn collapse_faster_than(mut self, threshold: Duration) -> Self {
+        self.format.collapse_faster_than = Some(threshold);
         self
     }
 
-    /// Sets if the time is shown
-    pub fn show_time(mut self, show: bool) -> Self {
-        self.format.show_time = show;
+    /// Sets the level of the events which prevent a fast span from being collapsed
+    pub fn collapse_keep_level(mut self, level: Level) -> Self {
+        self.format.collapse_keep_level = level;
         self
     }
 
-    /// Sets if the target is shown
-    pub fn show_target(mut self, show: bool) -> Self {
-        self.format.show_target = show;
+    /// Sets a duration above which the span exit line is marked as slow (`⚠ SLOW`, in red)
+    pub fn slow_threshold(mut self, threshold: Duration) -> Self {
+        self.format.slow_threshold = Some(threshold);
         self
     }
 
-    /// Sets if the file info is shown
-    pub fn show_file_info(mut self, show: bool) -> Self {
-        self.format.show_file_info = show;
+    /// Sets if the number of events within the span (and its descendants) is shown on the exit
+    /// line (eg `(7 events)`)
+    pub fn show_event_count(mut self, show: bool) -> Self {
+        self.format.show_event_count = show;
         self
     }
 
-    /// Sets if the span inline info is shown
-    pub fn show_span_info(mut self, show: bool) -> Self {
-        self.format.show_span_info = show;
+    /// Sets if events emitted on a span which is not entered are marked with `(span not active)`
+    ///
+    /// In async code, an event may be emitted with an explicit parent span between two polls of
+    /// the span future, which usually indicates a missing instrumentation.
+    pub fn warn_late_events(mut self, enabled: bool) -> Self {
+        self.format.warn_late_events = enabled;
         self
     }
 
-    /// Sets the indentation (in x spaces)
-    pub fn indent(mut self, indent: usize) -> Self {
-        self.format.indent = indent;
+    /// Sets if events emitted during a panic (e.g. while unwinding) are marked
+    pub fn mark_panics(mut self, mark: bool) -> Self {
+        self.format.mark_panics = mark;
         self
     }
 }
 
+/// Span attributes defining the span status
+const SPAN_STATUS_ATTRS: [&str; 2] = ["otel.status_code", "_status"];
+
+/// Status of a span
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SpanStatus {
+    /// Success
+    Ok,
+    /// Failure
+    Error,
+}
+
 /// A span extension for the span record
 #[derive(Debug)]
 struct SpanExtRecord {
     /// Level within the tree
     tree_level: usize,
+    /// Sequence number
+    seq: u64,
     /// Span ID
     id: u64,
+    /// IDs of the span ancestors, from the root to the parent
+    ancestor_ids: Vec<u64>,
+    /// ID of the tokio task which created the span
+    task_id: Option<String>,
     /// Span name
     name: &'static str,
     /// Span target
@@ -148,9 +2608,31 @@ struct SpanExtRecord {
     /// Line
     line: u32,
     /// Span attributes
-    attrs: HashMap<&'static str, String>,
-    /// Entered time
-    entered: Instant,
+    attrs: Fields,
+    /// Created time
+    created: Instant,
+    /// Created time, since the layer was built
+    uptime: Duration,
+    /// Created time (wall clock)
+    timestamp: time::OffsetDateTime,
+    /// First entered time
+    entered: Option<Instant>,
+    /// Number of times the span is currently entered (0 if the span is not active)
+    active: usize,
+    /// Time the span was last entered, while it is active
+    last_entered: Option<Instant>,
+    /// Time spent entered, excluding the current enter
+    busy: Duration,
+    /// Time spent entered by the closed children
+    children_busy: Duration,
+    /// Number of events within the span and its descendants (counted if shown)
+    event_count: usize,
+    /// Closed time
+    closed: Option<Instant>,
+    /// The span is printed (not filtered out, and sampled)
+    sampled: bool,
+    /// The lazy attributes are recorded
+    lazy_attrs: bool,
     /// Events within the span
     events: Vec<EventRecord>,
     // children
@@ -159,8 +2641,51 @@ struct SpanExtRecord {
 
 impl tracing::field::Visit for SpanExtRecord {
     fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
-        let value = format!("{value:?}");
-        self.attrs.insert(field.name(), value);
+        match field.name().strip_prefix(LAZY_FIELD_PREFIX) {
+            Some(name) if self.lazy_attrs => self.attrs.insert(name, format!("{value:?}")),
+            Some(_) => {}
+            None => self.attrs.record_debug(field, value),
+        }
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        if field.name().starts_with(LAZY_FIELD_PREFIX) {
+            self.record_debug(field, &value);
+        } else {
+            self.attrs.record_str(field, value);
+        }
+    }
+
+    fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
+        if field.name().starts_with(LAZY_FIELD_PREFIX) {
+            self.record_debug(field, &value);
+        } else {
+            self.attrs.record_bool(field, value);
+        }
+    }
+
+    fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
+        if field.name().starts_with(LAZY_FIELD_PREFIX) {
+            self.record_debug(field, &value);
+        } else {
+            self.attrs.record_f64(field, value);
+        }
+    }
+
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
+        if field.name().starts_with(LAZY_FIELD_PREFIX) {
+            self.record_debug(field, &value);
+        } else {
+            self.attrs.record_i64(field, value);
+        }
+    }
+
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        if field.name().starts_with(LAZY_FIELD_PREFIX) {
+            self.record_debug(field, &value);
+        } else {
+            self.attrs.record_u64(field, value);
+        }
     }
 }
 
@@ -170,7 +2695,7 @@ impl SpanExtRecord {
     /// Instantiates from a [SpanRef]
     ///
     /// NB: attributes are not collected yet
-    fn new_from_span_ref<S>(span_ref: &SpanRef<S>) -> Self
+    fn new_from_span_ref<S>(span_ref: &SpanRef<S>, seq: u64) -> Self
     where
         S: for<'b> tracing_subscriber::registry::LookupSpan<'b>,
     {
@@ -184,205 +2709,861 @@ impl SpanExtRecord {
 
         Self {
             tree_level,
+            seq,
             id: span_ref.id().into_u64(),
+            ancestor_ids: span_ref
+                .scope()
+                .from_root()
+                .map(|s| s.id().into_u64())
+                .filter(|id| *id != span_ref.id().into_u64())
+                .collect(),
+            task_id: None,
             name: span_ref.name(),
             target: span_ref.metadata().target().to_string(),
             file: span_ref.metadata().file().unwrap_or("").to_string(),
             line: span_ref.metadata().line().unwrap_or(0),
-            attrs: HashMap::new(),
-            entered: Instant::now(),
+            attrs: Fields::default(),
+            created: Instant::now(),
+            uptime: Duration::ZERO,
+            timestamp: time::OffsetDateTime::now_utc(),
+            entered: None,
+            active: 0,
+            last_entered: None,
+            busy: Duration::ZERO,
+            children_busy: Duration::ZERO,
+            event_count: 0,
+            closed: None,
+            sampled: true,
+            lazy_attrs: true,
             events: Vec::new(),
             children: Vec::new(),
         }
     }
 
+    /// Returns the span duration (until now if the span is not closed yet)
+    fn duration(&self) -> Duration {
+        self.closed.unwrap_or_else(Instant::now) - self.created
+    }
+
+    /// Returns the time spent entered (until now if the span is active)
+    fn busy(&self) -> Duration {
+        self.busy
+            + self
+                .last_entered
+                .map_or(Duration::ZERO, |entered| entered.elapsed())
+    }
+
+    /// Returns the time spent not entered, since the span creation
+    fn idle(&self) -> Duration {
+        self.duration().saturating_sub(self.busy())
+    }
+
+    /// Returns the delay between the span creation and its first enter
+    fn schedule_delay(&self) -> Option<Duration> {
+        self.entered.map(|entered| entered - self.created)
+    }
+
+    /// Returns the span status, if set by a status attribute
+    fn status(&self) -> Option<SpanStatus> {
+        SPAN_STATUS_ATTRS.iter().find_map(|attr| {
+            let value = self.attrs.get(attr)?;
+            match value.trim_matches('"').to_lowercase().as_str() {
+                "ok" => Some(SpanStatus::Ok),
+                "error" => Some(SpanStatus::Error),
+                _ => None,
+            }
+        })
+    }
+
+    /// Returns the label of the span entry (eg `{name}`)
+    fn entry_label(&self, opts: &PrettyFormatOptions) -> String {
+        match &opts.span_symbols {
+            Some((enter, _)) if opts.wrapped => {
+                let label = format!("{enter} {}", self.name);
+                if opts.color_span_symbols {
+                    opts.style(&label, |t| t.green())
+                } else {
+                    opts.style(&label, |t| t.magenta())
+                }
+            }
+            _ => opts.style(&opts.bracketed_name(self.name), |t| t.magenta()),
+        }
+    }
+
+    /// Returns the label of the span exit (eg `!{name}`), colored by the span status
+    fn exit_label(&self, opts: &PrettyFormatOptions) -> String {
+        let label = match &opts.span_symbols {
+            Some((_, exit)) if opts.wrapped => format!("{exit} {}", self.name),
+            _ => format!("!{}", opts.bracketed_name(self.name)),
+        };
+        match self.status() {
+            Some(SpanStatus::Ok) => opts.style(&label, |t| t.green()),
+            Some(SpanStatus::Error) => opts.style(&label, |t| t.red()),
+            None if opts.wrapped && opts.span_symbols.is_some() && opts.color_span_symbols => {
+                opts.style(&label, |t| t.blue())
+            }
+            None => opts.style(&label, |t| t.magenta()),
+        }
+    }
+
     /// Serializes the span entry
-    fn serialize_span_entry(&self, opts: &PrettyFormatOptions) -> Vec<u8> {
-        if opts.events_only {
+    ///
+    /// When `hide_redundant_attrs` is set, attributes identical to the parent attributes are skipped.
+    fn serialize_span_entry(
+        &self,
+        opts: &PrettyFormatOptions,
+        parent_attrs: Option<&Fields>,
+    ) -> Vec<u8> {
+        if opts.events_only || opts.output_format != OutputFormat::Pretty || !self.sampled {
+            return vec![];
+        }
+
+        let mut buf = take_buffer();
+        let field_indent = self.write_span_entry_head(&mut buf, opts);
+        let show_attrs = matches!(
+            opts.attr_placement,
+            AttrPlacement::Enter | AttrPlacement::Both
+        );
+        self.write_span_entry_fields(&mut buf, opts, parent_attrs, field_indent, show_attrs);
+        buf
+    }
+
+    /// Serializes a span without events nor children, with its entry and exit on the same line
+    ///
+    /// eg `{name}..!{name} 3us`, followed by the span fields. The attributes are shown once, unless
+    /// they are not placed at all.
+    fn serialize_span_collapsed(
+        &self,
+        opts: &PrettyFormatOptions,
+        parent_attrs: Option<&Fields>,
+        parent_duration: Option<Duration>,
+    ) -> Vec<u8> {
+        if opts.events_only || opts.output_format != OutputFormat::Pretty || !self.sampled {
             return vec![];
         }
 
-        let mut buf: Vec<u8> = vec![];
+        let mut buf = take_buffer();
+        let field_indent = self.write_span_entry_head(&mut buf, opts);
+        write!(buf, "..{}", self.exit_label(opts)).unwrap();
+        self.write_span_exit_timing(&mut buf, opts, parent_duration);
+        let show_attrs = opts.attr_placement != AttrPlacement::None;
+        self.write_span_entry_fields(&mut buf, opts, parent_attrs, field_indent, show_attrs);
+        buf
+    }
+
+    /// Writes the first line of the span entry (up to the label), and returns the field indent
+    fn write_span_entry_head(&self, buf: &mut Vec<u8>, opts: &PrettyFormatOptions) -> usize {
+        let marker_width = opts.write_kind_marker(buf, 'S');
 
-        let tree_indent = if opts.wrapped {
-            self.tree_level * opts.indent
-        } else {
-            0
-        };
-        let tree_indent_str = " ".repeat(tree_indent);
-        write!(buf, "{}", tree_indent_str).unwrap();
+        let tree_indent = opts.write_line_indent(buf, self.tree_level);
 
         if !opts.wrapped {
-            write!(buf, "{:w$}", format!("-->"), w = opts.indent).unwrap();
+            write!(buf, "{:w$}", opts.span_enter_marker, w = opts.indent).unwrap();
+        }
+        write!(buf, "{}", self.entry_label(opts)).unwrap();
+        if self.entered.is_none() {
+            write!(buf, " {}", opts.style("(never entered)", |t| t.dimmed())).unwrap();
         }
-        write!(buf, "{}", format!("{{{}}}", self.name).magenta()).unwrap();
 
-        let field_indent = tree_indent + opts.indent;
-        let field_indent_str = " ".repeat(field_indent);
-        let field_new_line = if opts.oneline {
-            " ".to_string()
-        } else {
-            format!("\n{field_indent_str}")
-        };
+        tree_indent + marker_width + opts.field_offset()
+    }
 
-        if opts.show_time {
-            let time_str = time::OffsetDateTime::now_utc()
-                .format(opts.time_format)
-                .expect("invalid datetime");
-            let line = format!("{}: {}", "time".italic(), time_str);
-            write!(buf, "{field_new_line}{}", line.dimmed()).unwrap();
-        };
+    /// Writes the fields of the span entry (time, span info, and attributes if shown)
+    fn write_span_entry_fields(
+        &self,
+        buf: &mut Vec<u8>,
+        opts: &PrettyFormatOptions,
+        parent_attrs: Option<&Fields>,
+        field_indent: usize,
+        show_attrs: bool,
+    ) {
+        let field_new_line = opts.field_new_line(self.tree_level, field_indent);
+        let shows = |field: &str| opts.shows_span_oneline_field(field);
 
-        // span info
-        if opts.show_span_info {
-            let span_id = format!("{}: {}", "span.id".italic(), self.id);
-            write!(buf, "{field_new_line}{}", span_id.dimmed()).unwrap();
-        }
+        for meta_field in &opts.metadata_order {
+            match meta_field {
+                MetaField::Time => {
+                    if opts.shows_time() && shows("time") {
+                        let time_str = opts.time_str(self.timestamp, self.uptime);
+                        opts.write_meta(buf, &field_new_line, "time", time_str);
+                    };
 
-        if opts.show_target {
-            let target = format!("{}: {}", "target".italic(), self.target);
-            write!(buf, "{field_new_line}{}", target.dimmed()).unwrap();
-        }
+                    if opts.show_schedule_delay && shows("scheduled") {
+                        if let Some(delay) = self.schedule_delay().filter(|d| d.as_micros() > 0) {
+                            let delay = format!("{}us", delay.as_micros());
+                            opts.write_meta(buf, &field_new_line, "scheduled", delay);
+                        }
+                    }
+                }
+                MetaField::Span => {
+                    if opts.show_span_info && shows("span.id") {
+                        opts.write_meta(buf, &field_new_line, "span.id", self.id);
+                    }
 
-        if opts.show_file_info {
-            let target = format!("{}: {}:{}", "file".italic(), self.file, self.line);
-            write!(buf, "{field_new_line}{}", target.dimmed()).unwrap();
+                    if let Some(task_id) = self.task_id.as_ref().filter(|_| shows("task.id")) {
+                        opts.write_meta(buf, &field_new_line, "task.id", task_id);
+                    }
+
+                    if opts.show_ancestor_ids && !self.ancestor_ids.is_empty() && shows("parents") {
+                        let parents = format!("{:?}", self.ancestor_ids);
+                        opts.write_meta(buf, &field_new_line, "parents", parents);
+                    }
+                }
+                MetaField::Target => {
+                    if opts.show_target && shows("target") {
+                        opts.write_meta(buf, &field_new_line, "target", &self.target);
+                    }
+                }
+                MetaField::File => {
+                    if let Some(file_info) = opts
+                        .file_info(&self.file, self.line)
+                        .filter(|_| shows("file"))
+                    {
+                        let file_info = opts.file_link(&self.file, file_info);
+                        opts.write_meta(buf, &field_new_line, "file", file_info);
+                    }
+                }
+            }
         }
 
         // span attributes
-        for (k, v) in &self.attrs {
-            write!(buf, "{field_new_line}{}={}", k.to_string().italic(), v).unwrap();
+        for (k, v) in self.attrs.iter().filter(|(k, _)| show_attrs && shows(k)) {
+            let redundant = parent_attrs
+                .and_then(|attrs| attrs.get(k))
+                .is_some_and(|parent_v| parent_v == v);
+            if opts.hide_redundant_attrs && redundant {
+                continue;
+            }
+            opts.write_field(buf, &field_new_line, k, &v);
         }
-
-        buf
     }
 
     /// Serializes the span exit
-    fn serialize_span_exit(&self, opts: &PrettyFormatOptions) -> Vec<u8> {
-        if opts.events_only {
+    ///
+    /// The parent duration is the reference of the duration bar (defaults to the span duration).
+    fn serialize_span_exit(
+        &self,
+        opts: &PrettyFormatOptions,
+        parent_duration: Option<Duration>,
+    ) -> Vec<u8> {
+        if opts.events_only || opts.output_format != OutputFormat::Pretty || !self.sampled {
             return vec![];
         }
 
-        let mut buf: Vec<u8> = vec![];
+        let mut buf = take_buffer();
+        let marker_width = opts.write_kind_marker(&mut buf, 'S');
 
-        let tree_indent = if opts.wrapped {
-            self.tree_level * opts.indent
-        } else {
-            0
-        };
-        let tree_indent_str = " ".repeat(tree_indent);
-        write!(buf, "{}", tree_indent_str).unwrap();
+        let tree_indent = opts.write_line_indent(&mut buf, self.tree_level);
 
         if !opts.wrapped {
-            write!(buf, "{:w$}", format!("<--"), w = opts.indent).unwrap();
+            write!(buf, "{:w$}", opts.span_exit_marker, w = opts.indent).unwrap();
+        }
+        write!(buf, "{}", self.exit_label(opts)).unwrap();
+        self.write_span_exit_timing(&mut buf, opts, parent_duration);
+
+        // span attributes (final values)
+        if matches!(
+            opts.attr_placement,
+            AttrPlacement::Exit | AttrPlacement::Both
+        ) {
+            let field_new_line = opts.field_new_line(
+                self.tree_level,
+                tree_indent + marker_width + opts.field_offset(),
+            );
+            for (k, v) in self.attrs.iter() {
+                opts.write_field(&mut buf, &field_new_line, k, &v);
+            }
         }
-        write!(buf, "{}", format!("!{{{}}}", self.name).magenta()).unwrap();
 
+        buf
+    }
+
+    /// Writes the span ID and duration, following the exit label
+    fn write_span_exit_timing(
+        &self,
+        buf: &mut Vec<u8>,
+        opts: &PrettyFormatOptions,
+        parent_duration: Option<Duration>,
+    ) {
         // span info
         if opts.show_span_info {
-            let span_id = format!("({}={})", "id".italic(), self.id);
-            write!(buf, " {}", span_id.dimmed()).unwrap();
+            let span_id = format!("({}={})", opts.style("id", |t| t.italic()), self.id);
+            write!(buf, " {}", opts.style(&span_id, |t| t.dimmed())).unwrap();
+        }
+
+        let duration = self.duration();
+        let duration_str = format_duration(duration, opts.duration_human);
+        let budget = opts.span_budget.as_ref().and_then(|f| (f.0)(self.name));
+        let is_slow = opts.slow_threshold.is_some_and(|t| duration > t);
+        match budget {
+            Some(budget) => {
+                let budget_str = format_duration(budget, opts.duration_human);
+                // NB: no ratio for a zero budget
+                let label = if budget.is_zero() {
+                    format!("{duration_str}/{budget_str}")
+                } else {
+                    let ratio = duration.as_secs_f64() / budget.as_secs_f64();
+                    format!("{duration_str}/{budget_str} ({:.0}%)", ratio * 100.0)
+                };
+                let label = if duration > budget {
+                    opts.style(&label, |t| t.red())
+                } else {
+                    opts.style(&label, |t| t.green())
+                };
+                write!(buf, " {label}").unwrap();
+            }
+            None if is_slow => write!(buf, " {}", opts.style(&duration_str, |t| t.red())).unwrap(),
+            None => write!(buf, " {}", opts.style(&duration_str, |t| t.dimmed())).unwrap(),
         }
+        if is_slow {
+            write!(buf, " {}", opts.style("⚠ SLOW", |t| t.red().bold())).unwrap();
+        }
+        if opts.show_event_count {
+            let count = match self.event_count {
+                1 => "(1 event)".to_string(),
+                count => format!("({count} events)"),
+            };
+            write!(buf, " {}", opts.style(&count, |t| t.dimmed())).unwrap();
+        }
+        if opts.show_busy_idle {
+            let busy_idle = format!(
+                "({}={} {}={})",
+                opts.style("busy", |t| t.italic()),
+                format_duration(self.busy(), opts.duration_human),
+                opts.style("idle", |t| t.italic()),
+                format_duration(self.idle(), opts.duration_human)
+            );
+            write!(buf, " {}", opts.style(&busy_idle, |t| t.dimmed())).unwrap();
+        }
+        if opts.duration_bars && opts.wrapped {
+            let reference = parent_duration.unwrap_or(duration);
+            let bar = duration_bar(duration, reference, opts.duration_bar_width);
+            write!(buf, " {}", opts.style(&bar, |t| t.dimmed())).unwrap();
+        }
+        if opts.machine_duration {
+            let duration_ns = format!(
+                "{}={}",
+                opts.style("duration_ns", |t| t.italic()),
+                duration.as_nanos()
+            );
+            write!(buf, " {}", opts.style(&duration_ns, |t| t.dimmed())).unwrap();
+        }
+    }
+}
+
+impl SpanExtRecord {
+    /// Checks if the span or its descendants contain WARN or ERROR events
+    fn has_problems(&self) -> bool {
+        self.has_events_at(Level::WARN)
+    }
+
+    /// Collapses the children faster than a threshold (unless they have events at the keep level)
+    ///
+    /// The events and children of a collapsed child are moved to the span, one tree level up, in
+    /// chronological order.
+    fn collapse_fast_children(&mut self, threshold: Duration, keep_level: Level) {
+        self.hoist_children(&|child| {
+            child.duration() < threshold && !child.has_events_at(keep_level)
+        });
+    }
+
+    /// Hides the children which were never entered
+    ///
+    /// The events and children of a hidden child are moved to the span, one tree level up.
+    fn hide_unentered_children(&mut self) {
+        self.hoist_children(&|child| child.entered.is_none());
+    }
+
+    /// Removes the matching descendants, and moves their events and children to their parent, one
+    /// tree level up, in chronological order
+    fn hoist_children(&mut self, hoists: &impl Fn(&SpanExtRecord) -> bool) {
+        let mut hoisted = false;
+        for mut child in std::mem::take(&mut self.children) {
+            child.hoist_children(hoists);
+            if hoists(&child) {
+                child.level_up();
+                self.events.append(&mut child.events);
+                self.children.append(&mut child.children);
+                hoisted = true;
+            } else {
+                self.children.push(child);
+            }
+        }
+        if hoisted {
+            self.events.sort_by_key(|event| event.seq);
+            self.children.sort_by_key(|child| child.seq);
+        }
+    }
+
+    /// Moves the span tree one tree level up
+    fn level_up(&mut self) {
+        self.tree_level = self.tree_level.saturating_sub(1);
+        for event in &mut self.events {
+            if let Some((level, _, _)) = &mut event.span {
+                *level = level.saturating_sub(1);
+            }
+        }
+        for child in &mut self.children {
+            child.level_up();
+        }
+    }
+
+    /// Checks if the span tree contains events at a level (or more severe)
+    fn has_events_at(&self, level: Level) -> bool {
+        self.events.iter().any(|e| e.level <= level)
+            || self.children.iter().any(|c| c.has_events_at(level))
+    }
+
+    /// Hashes the structure of the span tree
+    ///
+    /// Two trees have the same structure if they have the same span names, nesting, and event
+    /// levels and messages, in the same order. Attributes, fields, timings and IDs are ignored.
+    fn hash_structure(&self, hasher: &mut impl Hasher) {
+        self.name.hash(hasher);
+        for event in &self.events {
+            event.level.as_str().hash(hasher);
+            event.message.hash(hasher);
+        }
+        self.children.len().hash(hasher);
+        for child in &self.children {
+            child.hash_structure(hasher);
+        }
+    }
+
+    /// Checks if the span tree contains an event in focus
+    fn contains_focus(&self, focus: &FocusFn) -> bool {
+        self.events.iter().any(|e| e.in_focus(focus))
+            || self.children.iter().any(|c| c.contains_focus(focus))
+    }
+
+    /// Returns the number of events and spans within the span tree (exc. the span itself)
+    fn tree_counts(&self) -> (usize, usize) {
+        self.children
+            .iter()
+            .fold((self.events.len(), self.children.len()), |acc, child| {
+                let (events, spans) = child.tree_counts();
+                (acc.0 + events, acc.1 + spans)
+            })
+    }
+
+    /// Serializes the span tree as a single summary line
+    fn serialize_span_summary(&self, opts: &PrettyFormatOptions) -> Vec<u8> {
+        if opts.output_format != OutputFormat::Pretty || !self.sampled {
+            return vec![];
+        }
+
+        let mut buf = take_buffer();
+        opts.write_kind_marker(&mut buf, 'S');
+
+        opts.write_line_indent(&mut buf, self.tree_level);
+        write!(buf, "{}", self.entry_label(opts)).unwrap();
+
+        let (events, spans) = self.tree_counts();
+        let duration = format_duration(self.duration(), opts.duration_human);
+        let summary = format!("(collapsed: {events} events, {spans} spans) {duration}");
+        write!(buf, " {}", opts.style(&summary, |t| t.dimmed())).unwrap();
+
+        buf
+    }
+}
 
-        let duration_us = self.entered.elapsed().as_micros();
-        write!(buf, " {}", format!("{duration_us}us").dimmed()).unwrap();
+/// Formats a duration
+///
+/// In human mode, the unit depends on the duration: `us` under 1ms, `ms` under 1s, otherwise `s`
+/// (eg `500us`, `5.20ms`, `2.00s`). Otherwise, the duration is in microseconds.
+pub(crate) fn format_duration(duration: Duration, human: bool) -> String {
+    if !human || duration < Duration::from_millis(1) {
+        format!("{}us", duration.as_micros())
+    } else if duration < Duration::from_secs(1) {
+        format!("{:.2}ms", duration.as_secs_f64() * 1000.0)
+    } else {
+        format!("{:.2}s", duration.as_secs_f64())
+    }
+}
+
+/// Returns a bar showing a duration relative to a reference duration (eg `████░░ 60%`)
+fn duration_bar(duration: Duration, reference: Duration, width: usize) -> String {
+    let ratio = if reference.is_zero() {
+        1.0
+    } else {
+        (duration.as_secs_f64() / reference.as_secs_f64()).min(1.0)
+    };
+    let filled = (ratio * width as f64).round() as usize;
+    format!(
+        "{}{} {:.0}%",
+        "█".repeat(filled),
+        "░".repeat(width - filled),
+        ratio * 100.0
+    )
+}
+
+/// Strips the ANSI escape sequences (CSI and OSC) from a buffer
+pub(crate) fn strip_ansi(buf: &[u8]) -> Vec<u8> {
+    const ESC: u8 = 0x1b;
+    const BEL: u8 = 0x07;
+
+    let mut stripped = Vec::with_capacity(buf.len());
+    let mut i = 0;
+    while i < buf.len() {
+        if buf[i] != ESC {
+            stripped.push(buf[i]);
+            i += 1;
+            continue;
+        }
+        match buf.get(i + 1) {
+            // CSI: ESC [ params final byte (0x40-0x7E)
+            Some(b'[') => {
+                i += 2;
+                while i < buf.len() && !(0x40..=0x7e).contains(&buf[i]) {
+                    i += 1;
+                }
+                i += 1;
+            }
+            // OSC: ESC ] ... terminated by BEL or ESC \
+            Some(b']') => {
+                i += 2;
+                while i < buf.len() {
+                    if buf[i] == BEL {
+                        i += 1;
+                        break;
+                    }
+                    if buf[i] == ESC && buf.get(i + 1) == Some(&b'\\') {
+                        i += 2;
+                        break;
+                    }
+                    i += 1;
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    stripped
+}
+
+/// An item within a span tree
+#[derive(Debug)]
+enum TreeItem<'a> {
+    /// Event of the span
+    Event(&'a EventRecord),
+    /// Child span
+    Child(&'a SpanExtRecord),
+}
 
-        buf
+impl SpanExtRecord {
+    /// Returns the span events and children, in output order
+    ///
+    /// If `group_by_level` is set, the events are grouped by level (most severe first), in chronological
+    /// order within a level. This does not apply when the children are interleaved.
+    fn tree_items(&self, position: ChildrenPosition, group_by_level: bool) -> Vec<TreeItem<'_>> {
+        let mut events: Vec<_> = self.events.iter().collect();
+        if group_by_level {
+            events.sort_by_key(|event| event.level);
+        }
+        let events = events.into_iter().map(TreeItem::Event);
+        let children = self.children.iter().map(TreeItem::Child);
+        match position {
+            ChildrenPosition::After => events.chain(children).collect(),
+            ChildrenPosition::Before => children.chain(events).collect(),
+            ChildrenPosition::Interleaved => {
+                let mut items: Vec<_> = events.chain(children).collect();
+                items.sort_by_key(|item| match item {
+                    TreeItem::Event(event) => event.seq,
+                    TreeItem::Child(child) => child.seq,
+                });
+                items
+            }
+        }
     }
 }
 
 /// An event record
 #[derive(Debug)]
 struct EventRecord {
+    /// Sequence number
+    seq: u64,
+    /// Created time, since the layer was built
+    uptime: Duration,
+    /// Created time (wall clock)
+    timestamp: time::OffsetDateTime,
     level: Level,
     target: String,
     file: String,
     line: u32,
     message: String,
-    meta_fields: HashMap<&'static str, String>,
+    meta_fields: Fields,
     /// Span info (tree level, id, name)
     span: Option<(usize, u64, String)>,
+    /// Target of the event span
+    span_target: Option<String>,
+    /// Attributes of the event span (only recorded for the JSON format)
+    #[cfg(feature = "serde")]
+    span_fields: Fields,
+    /// Context fields (after the event fields)
+    context_fields: Vec<(String, String)>,
+    /// Span ancestors, from the root to the event span (id, name)
+    ancestors: Vec<(u64, &'static str)>,
+    /// The event was emitted while the thread was panicking
+    panicking: bool,
+    /// The event was emitted on a span which was not entered
+    late: bool,
+    /// ID of the tokio task which emitted the event
+    task_id: Option<String>,
+    /// Name and ID of the thread which emitted the event
+    thread: Option<String>,
+    /// Time since the previous event of the thread
+    delta: Option<Duration>,
 }
 
 impl EventRecord {
-    /// Serializes an event
-    fn serialize(&self, opts: &PrettyFormatOptions) -> Vec<u8> {
-        let mut buf: Vec<u8> = vec![];
-
-        let tree_indent = if opts.wrapped {
-            let tree_level = self.span.as_ref().map(|(l, _, _)| *l).unwrap_or(0);
-            tree_level * opts.indent
-        } else {
-            0
-        };
-        let tree_indent_str = " ".repeat(tree_indent);
-        write!(buf, "{}", tree_indent_str).unwrap();
-
-        let level_str = match self.level {
-            tracing::Level::TRACE => format!("{:w$}", "TRACE", w = opts.indent).magenta(),
-            tracing::Level::DEBUG => format!("{:w$}", "DEBUG", w = opts.indent).blue(),
-            tracing::Level::INFO => format!("{:w$}", "INFO", w = opts.indent).green(),
-            tracing::Level::WARN => format!("{:w$}", "WARN", w = opts.indent).yellow(),
-            tracing::Level::ERROR => format!("{:w$}", "ERROR", w = opts.indent).red(),
-        };
-        write!(buf, "{}", level_str).unwrap();
-        write!(buf, "{}", self.message).unwrap();
+    /// Checks if the event is in focus
+    fn in_focus(&self, focus: &FocusFn) -> bool {
+        focus(&self.message, &self.meta_fields)
+    }
 
-        let field_indent = tree_indent + opts.indent;
-        let field_indent_str = " ".repeat(field_indent);
-        let field_new_line = if opts.oneline {
-            " ".to_string()
-        } else {
-            format!("\n{field_indent_str}")
-        };
+    /// Returns the displayed level (label and level), overridden by the severity field if mapped
+    fn display_level(&self, opts: &PrettyFormatOptions) -> (String, Level) {
+        let severity = opts.severity_field.as_ref().and_then(|(field, mapping)| {
+            let value = self.meta_fields.get(field)?;
+            let value = value.trim_matches('"').to_lowercase();
+            let level = *mapping.get(&value)?;
+            Some((value.to_uppercase(), level))
+        });
+        severity.unwrap_or_else(|| {
+            let label = opts.level_style.label(self.level).to_string();
+            (label, self.level)
+        })
+    }
 
-        if opts.show_time {
-            let time_str = time::OffsetDateTime::now_utc()
-                .format(opts.time_format)
-                .expect("invalid datetime");
-            let line = format!("{}: {}", "time".italic(), time_str);
-            write!(buf, "{field_new_line}{}", line.dimmed()).unwrap();
+    /// Returns the span breadcrumb (eg `[root>child]`)
+    fn breadcrumb(&self, style: BreadcrumbStyle) -> Option<String> {
+        if self.ancestors.is_empty() {
+            return None;
+        }
+        let crumbs: Vec<String> = match style {
+            BreadcrumbStyle::Names => self
+                .ancestors
+                .iter()
+                .map(|(_, name)| name.to_string())
+                .collect(),
+            BreadcrumbStyle::Ids => self
+                .ancestors
+                .iter()
+                .map(|(id, _)| id.to_string())
+                .collect(),
+            BreadcrumbStyle::None => return None,
         };
+        Some(format!("[{}]", crumbs.join(">")))
+    }
 
-        // event context
-        if opts.show_span_info {
-            if let Some((_, id, name)) = &self.span {
-                let span_id = format!("{}: {}", "span.id".italic(), id);
-                write!(buf, "{field_new_line}{}", span_id.dimmed()).unwrap();
-
-                let span_name = format!(
-                    "{field_new_line}{}{} {}",
-                    "span.name".italic().dimmed(),
-                    ":".dimmed(),
-                    name.truecolor(191, 160, 217)
-                );
-                write!(buf, "{}", span_name.dimmed()).unwrap();
-            }
+    /// Returns a line summarizing the event (span path, message and target)
+    fn summary_line(&self, ansi: bool) -> String {
+        let mut line = String::new();
+        if let Some(breadcrumb) = self.breadcrumb(BreadcrumbStyle::Names) {
+            line.push_str(&styled(&breadcrumb, ansi, |t| t.dimmed()));
+            line.push(' ');
         }
+        let target = styled(&self.target, ansi, |t| t.dimmed());
+        line.push_str(&format!("{} {target}", self.message));
+        line
+    }
 
-        if opts.show_target {
-            let target = format!("{}: {}", "target".italic(), self.target);
-            write!(buf, "{field_new_line}{}", target.dimmed()).unwrap();
+    /// Serializes an event
+    ///
+    /// The machine-readable formats fail on values which cannot be represented (eg a timestamp out
+    /// of the RFC 3339 range).
+    fn try_serialize(&self, opts: &PrettyFormatOptions) -> std::io::Result<Vec<u8>> {
+        match opts.output_format {
+            OutputFormat::Pretty => Ok(self.serialize_pretty(opts)),
+            OutputFormat::Syslog => self.serialize_syslog(opts),
+            OutputFormat::Tsv => self.serialize_tsv(opts),
+            OutputFormat::Logfmt => self.serialize_logfmt(opts),
+            #[cfg(feature = "serde")]
+            OutputFormat::Yaml => self.serialize_yaml(opts),
+            #[cfg(feature = "serde")]
+            OutputFormat::Json => self.serialize_json(opts),
+            #[cfg(feature = "serde")]
+            OutputFormat::ChromeTrace => self.serialize_trace_event(),
         }
+    }
 
-        if opts.show_file_info {
-            let target = format!("{}: {}:{}", "file".italic(), self.file, self.line);
-            write!(buf, "{field_new_line}{}", target.dimmed()).unwrap();
+    /// Serializes an event (pretty format)
+    fn serialize_pretty(&self, opts: &PrettyFormatOptions) -> Vec<u8> {
+        if opts.compact {
+            return self.serialize_compact(opts);
         }
+        let mut buf = take_buffer();
 
-        // event fields
-        for (k, v) in &self.meta_fields {
-            write!(buf, "{field_new_line}{}={}", k.to_string().italic(), v).unwrap();
+        let field_indent = self.write_pretty_header(opts, &mut buf);
+        let tree_level = self.span.as_ref().map(|(l, _, _)| *l).unwrap_or(0);
+        let field_new_line = opts.field_new_line(tree_level, field_indent);
+        self.write_pretty_fields(opts, &mut buf, &field_new_line);
+
+        buf
+    }
+
+    /// Serializes an event (compact format)
+    ///
+    /// `LEVEL message key=value ...`, with multi-line values escaped
+    fn serialize_compact(&self, opts: &PrettyFormatOptions) -> Vec<u8> {
+        let mut buf = take_buffer();
+
+        let (label, level) = self.display_level(opts);
+        let label = opts.style(&label, |t| opts.level_style.paint(t, level));
+        write!(buf, "{} {}", label, self.message).unwrap();
+
+        let context_fields = self
+            .context_fields
+            .iter()
+            .map(|(k, v)| (k.as_str(), Cow::Borrowed(v.as_str())));
+        let fields = self
+            .meta_fields
+            .iter()
+            .map(|(k, v)| (k as &str, v))
+            .chain(context_fields)
+            .filter(|(_, v)| !opts.hides_value(v));
+        for (k, v) in fields {
+            let v = opts.truncate_value(&v).replace('\n', "\\n");
+            write!(buf, " {}{}={}", opts.field_prefix, k, v).unwrap();
         }
 
         buf
     }
+
+    /// Writes the first line of an event (pretty format), and returns the indentation of the fields
+    ///
+    /// In the header line mode, the time and span are also part of the first line.
+    fn write_pretty_header(&self, opts: &PrettyFormatOptions, buf: &mut Vec<u8>) -> usize {
+        let marker_width = opts.write_kind_marker(buf, 'E');
+
+        let tree_level = self.span.as_ref().map(|(l, _, _)| *l).unwrap_or(0);
+        let tree_indent = opts.write_line_indent(buf, tree_level);
+
+        let icon_width = if opts.target_icons.is_empty() { 0 } else { 2 };
+        if icon_width > 0 {
+            let icon = opts
+                .target_icons
+                .iter()
+                .filter(|(prefix, _)| self.target.starts_with(prefix.as_str()))
+                .max_by_key(|(prefix, _)| prefix.len())
+                .map(|(_, icon)| *icon)
+                .unwrap_or(' ');
+            write!(buf, "{icon} ").unwrap();
+        }
+
+        if opts.header_line && opts.shows_time() {
+            let time_str = opts.time_str(self.timestamp, self.uptime);
+            write!(buf, "{} ", opts.style(&time_str, |t| t.dimmed())).unwrap();
+        }
+
+        let (label, level) = self.display_level(opts);
+        let label = format!("{:w$}", label, w = LEVEL_LABEL_WIDTH);
+        let label = opts.style(&label, |t| opts.level_style.paint(t, level));
+        write!(buf, "{label} ").unwrap();
+        if self.panicking {
+            write!(buf, "{} ", opts.style("DURING PANIC", |t| t.red().bold())).unwrap();
+        }
+        if self.late {
+            write!(buf, "{} ", opts.style("(span not active)", |t| t.yellow())).unwrap();
+        }
+        if let Some(breadcrumb) = self.breadcrumb(opts.breadcrumb_style) {
+            write!(buf, "{} ", opts.style(&breadcrumb, |t| t.dimmed())).unwrap();
+        } else if let (true, true, Some((_, _, name))) =
+            (opts.header_line, opts.show_span_info, &self.span)
+        {
+            let name = opts.style(&format!("[{name}]"), |t| t.truecolor(191, 160, 217));
+            write!(buf, "{name} ").unwrap();
+        }
+        write!(buf, "{}", self.message).unwrap();
+
+        tree_indent + marker_width + icon_width + opts.field_offset()
+    }
+
+    /// Writes the fields of an event (pretty format), each preceded by the field separator
+    fn write_pretty_fields(
+        &self,
+        opts: &PrettyFormatOptions,
+        buf: &mut Vec<u8>,
+        field_new_line: &str,
+    ) {
+        for meta_field in &opts.metadata_order {
+            match meta_field {
+                MetaField::Time => {
+                    if opts.shows_time() && !opts.header_line {
+                        let time_str = opts.time_str(self.timestamp, self.uptime);
+                        opts.write_meta(buf, field_new_line, "time", time_str);
+                    };
+                    if let Some(delta) = self.delta {
+                        let delta_str = format!("+{}ms", delta.as_millis());
+                        opts.write_meta(buf, field_new_line, "delta", delta_str);
+                    }
+                }
+                MetaField::Span => {
+                    // event context
+                    if opts.show_span_info && !opts.header_line {
+                        if let Some((_, id, name)) = &self.span {
+                            opts.write_meta(buf, field_new_line, "span.id", id);
+
+                            let span_name = format!(
+                                "{}{} {}",
+                                opts.style("span.name", |t| t.italic().dimmed()),
+                                opts.style(":", |t| t.dimmed()),
+                                opts.style(name, |t| t.truecolor(191, 160, 217))
+                            );
+                            let span_name = opts.style(&span_name, |t| t.dimmed());
+                            write!(buf, "{field_new_line}{span_name}").unwrap();
+                        }
+                    }
+
+                    if let Some(task_id) = &self.task_id {
+                        opts.write_meta(buf, field_new_line, "task.id", task_id);
+                    }
+
+                    if let Some(thread) = &self.thread {
+                        opts.write_meta(buf, field_new_line, "thread", thread);
+                    }
+                }
+                MetaField::Target => {
+                    let redundant_target = self.span_target.as_ref() == Some(&self.target);
+                    if opts.show_target && !(opts.hide_redundant_target && redundant_target) {
+                        opts.write_meta(buf, field_new_line, "target", &self.target);
+                    }
+                }
+                MetaField::File => {
+                    if let Some(file_info) = opts.file_info(&self.file, self.line) {
+                        let file_info = opts.file_link(&self.file, file_info);
+                        opts.write_meta(buf, field_new_line, "file", file_info);
+                    }
+                }
+            }
+        }
+
+        // event fields
+        for (k, v) in self.meta_fields.iter() {
+            let color = opts
+                .numeric_value_style
+                .as_ref()
+                .zip(self.meta_fields.number(k))
+                .and_then(|(style, number)| (style.0)(number));
+            opts.write_colored_field(buf, field_new_line, k, &v, color);
+        }
+        for (k, v) in &self.context_fields {
+            opts.write_field(buf, field_new_line, k, v);
+        }
+    }
 }
 
 impl<S> tracing_subscriber::Layer<S> for PrettyConsoleLayer
 where
     S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
 {
+    fn on_layer(&mut self, _subscriber: &mut S) {
+        // NB: older Windows consoles only render ANSI escape codes with the virtual terminal processing
+        #[cfg(windows)]
+        if self.format.colorizes() {
+            console::enable_virtual_terminal();
+        }
+    }
+
     fn on_new_span(
         &self,
         attrs: &tracing::span::Attributes<'_>,
@@ -390,23 +3571,91 @@ where
         ctx: tracing_subscriber::layer::Context<'_, S>,
     ) {
         let span_ref = ctx.span(id).expect("span not found");
-        let record = SpanExtRecord::new_from_span_ref(&span_ref);
+        let mut record = SpanExtRecord::new_from_span_ref(&span_ref, self.next_seq());
+        record.uptime = record.created.saturating_duration_since(self.started);
+        record.sampled = self.is_enabled(span_ref.metadata()) && self.sample_span();
+        if self.format.show_task_id {
+            record.task_id = current_task_id();
+        }
+        record.lazy_attrs = record.sampled
+            && !self.format.events_only
+            && !matches!(
+                self.format.output_format,
+                OutputFormat::Syslog | OutputFormat::Tsv | OutputFormat::Logfmt
+            );
+        self.state.open_spans.lock().unwrap().insert(
+            record.id,
+            OpenSpanInfo {
+                id: record.id,
+                name: record.name,
+                created: record.created,
+            },
+        );
         SpanExtRecord::register_value(record, &span_ref);
         SpanExtRecord::record_attrs(&span_ref, attrs);
+        if !self.format.redacted_fields.is_empty() {
+            if let Some(record) = span_ref.extensions_mut().get_mut::<SpanExtRecord>() {
+                record.attrs.redact(&self.format.redacted_fields);
+            }
+        }
+    }
+
+    fn on_record(
+        &self,
+        id: &tracing::span::Id,
+        values: &tracing::span::Record<'_>,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        let span_ref = ctx.span(id).expect("span not found");
+        let mut extensions = span_ref.extensions_mut();
+        if let Some(record) = extensions.get_mut::<SpanExtRecord>() {
+            let mut fields = Fields::default();
+            values.record(&mut fields);
+            fields.redact(&self.format.redacted_fields);
+            record.attrs.merge(fields);
+        }
     }
 
     fn on_enter(&self, id: &tracing::span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
         let span_ref = ctx.span(id).expect("span not found");
 
-        let mut extensions = span_ref.extensions_mut();
+        // NB: the write lock is only held to update the times, the record is serialized under the
+        // read lock
+        let first_enter = {
+            let mut extensions = span_ref.extensions_mut();
+            let record = extensions
+                .get_mut::<SpanExtRecord>()
+                .expect("Extension not initialized");
+            let first_enter = record.entered.is_none();
+            let now = Instant::now();
+            record.entered.get_or_insert(now);
+            if record.active == 0 {
+                record.last_entered = Some(now);
+            }
+            record.active += 1;
+            first_enter
+        };
+
+        let extensions = span_ref.extensions();
         let record = extensions
-            .get_mut::<SpanExtRecord>()
+            .get::<SpanExtRecord>()
             .expect("Extension not initialized");
 
+        #[cfg(feature = "serde")]
+        if self.format.output_format == OutputFormat::ChromeTrace {
+            if record.sampled && !self.format.events_only {
+                self.write_trace_event(record.serialize_trace_event(chrome::Phase::Begin));
+            }
+            return;
+        }
         if !self.format.wrapped {
-            let buf = record.serialize_span_entry(&self.format);
+            let buf = record.serialize_span_entry(&self.format, None);
             if !buf.is_empty() {
-                eprintln!("{}", std::str::from_utf8(&buf).unwrap());
+                if first_enter && span_ref.parent().is_none() {
+                    self.print_root_separator();
+                }
+                self.print_sampling_summary();
+                self.write_buffer(RecordKind::Span, buf);
             }
         }
     }
@@ -414,21 +3663,86 @@ where
     fn on_exit(&self, id: &tracing::span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
         let span_ref = ctx.span(id).expect("span not found");
 
-        let mut extensions = span_ref.extensions_mut();
+        {
+            let mut extensions = span_ref.extensions_mut();
+            let record = extensions
+                .get_mut::<SpanExtRecord>()
+                .expect("Extension not initialized");
+            record.active = record.active.saturating_sub(1);
+            if record.active == 0 {
+                if let Some(entered) = record.last_entered.take() {
+                    record.busy += entered.elapsed();
+                }
+            }
+        }
+
+        let extensions = span_ref.extensions();
         let record = extensions
-            .get_mut::<SpanExtRecord>()
+            .get::<SpanExtRecord>()
             .expect("Extension not initialized");
 
+        #[cfg(feature = "serde")]
+        if self.format.output_format == OutputFormat::ChromeTrace {
+            if record.sampled && !self.format.events_only {
+                self.write_trace_event(record.serialize_trace_event(chrome::Phase::End));
+            }
+            return;
+        }
         if !self.format.wrapped {
-            let buf = record.serialize_span_exit(&self.format);
+            let buf = record.serialize_span_exit(&self.format, None);
             if !buf.is_empty() {
-                eprintln!("{}", std::str::from_utf8(&buf).unwrap());
+                self.write_buffer(RecordKind::Span, buf);
             }
         }
     }
 
     fn on_close(&self, id: tracing::span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
         let span_ref = ctx.span(&id).expect("span not found");
+        self.state.open_spans.lock().unwrap().remove(&id.into_u64());
+
+        if let Some(record) = span_ref.extensions_mut().get_mut::<SpanExtRecord>() {
+            record.closed = Some(Instant::now());
+
+            if self.format.folded_stacks && record.sampled {
+                let busy = record.busy();
+                let path = span_ref
+                    .scope()
+                    .from_root()
+                    .map(|s| s.name())
+                    .collect::<Vec<_>>()
+                    .join(";");
+                let self_busy = busy.saturating_sub(record.children_busy);
+                *self
+                    .state
+                    .folded_stacks
+                    .lock()
+                    .unwrap()
+                    .entry(path)
+                    .or_default() += self_busy.as_micros() as u64;
+                if let Some(parent_ref) = span_ref.parent() {
+                    if let Some(parent) = parent_ref.extensions_mut().get_mut::<SpanExtRecord>() {
+                        parent.children_busy += busy;
+                    }
+                }
+            }
+
+            // NB: in streaming mode, a span which was never entered has not been printed yet
+            if !self.format.wrapped && !self.format.hide_unentered_spans && record.entered.is_none()
+            {
+                let buf = record.serialize_span_entry(&self.format, None);
+                if !buf.is_empty() {
+                    self.write_buffer(RecordKind::Span, buf);
+                }
+            }
+            #[cfg(feature = "serde")]
+            if let Some(file) = &mut *self.state.recorder.lock().unwrap() {
+                let parent = span_ref.parent().map(|p| p.id().into_u64());
+                let span = replay::CapturedSpan::from_record(record, parent);
+                if let Err(err) = replay::write_captured(file, &replay::Captured::Span(span)) {
+                    self.state.writers.lock().unwrap().report_error(err);
+                }
+            }
+        }
 
         // When wrapping, if the span has a parent, we record it as a child of the parent.
         // If it is the root, the span tree is outputted
@@ -452,50 +3766,76 @@ where
                 let record = extensions
                     .remove::<SpanExtRecord>()
                     .expect("Extension not initialized");
-                self.output_root_tree(&record);
+                self.output_root(record);
             }
         }
     }
 
     fn on_event(&self, event: &tracing::Event<'_>, ctx: tracing_subscriber::layer::Context<'_, S>) {
-        let visitor = EventVisitor::record_event(event);
+        if !self.is_enabled(event.metadata()) {
+            return;
+        }
+        if let Some(level_fn) = &self.format.span_event_level {
+            let min_level = ctx.event_span(event).and_then(|span_ref| {
+                let extensions = span_ref.extensions();
+                let record = extensions.get::<SpanExtRecord>()?;
+                Some((level_fn.0)(&record.attrs))
+            });
+            if min_level.is_some_and(|min_level| *event.metadata().level() > min_level) {
+                return;
+            }
+        }
 
-        let evt_record = EventRecord {
-            level: *event.metadata().level(),
-            target: event.metadata().target().to_string(),
-            file: event.metadata().file().unwrap_or("").to_string(),
-            line: event.metadata().line().unwrap_or(0),
-            message: visitor.message().to_string(),
-            meta_fields: visitor
-                .meta_fields()
-                .iter()
-                .map(|(k, v)| (*k, v.to_string()))
-                .collect(),
-            span: ctx.current_span().id().map(|id| {
-                let parent_ref = ctx.span(id).expect("span not found");
-                let mut extensions = parent_ref.extensions_mut();
-                let span_record = extensions
-                    .get_mut::<SpanExtRecord>()
-                    .expect("Extension not initialized");
-                (
-                    span_record.tree_level + 1,
-                    id.into_u64(),
-                    ctx.current_span().metadata().unwrap().name().to_string(),
-                )
-            }),
-        };
+        let evt_record = self.event_record(event, &ctx);
+        #[cfg(feature = "serde")]
+        if let Some(file) = &mut *self.state.recorder.lock().unwrap() {
+            let event = replay::CapturedEvent::from_record(&evt_record);
+            if let Err(err) = replay::write_captured(file, &replay::Captured::Event(event)) {
+                self.state.writers.lock().unwrap().report_error(err);
+            }
+        }
+        if self.format.error_summary_on_exit && evt_record.level == Level::ERROR {
+            self.state
+                .errors
+                .lock()
+                .unwrap()
+                .push(evt_record.summary_line(self.format.ansi));
+        }
+        if self.format.target_summary_on_exit {
+            *self
+                .state
+                .target_counts
+                .lock()
+                .unwrap()
+                .entry(evt_record.target.clone())
+                .or_default() += 1;
+        }
+        if self.format.show_event_count {
+            if let Some(span_ref) = ctx.event_span(event) {
+                for span_ref in span_ref.scope() {
+                    if let Some(record) = span_ref.extensions_mut().get_mut::<SpanExtRecord>() {
+                        record.event_count += 1;
+                    }
+                }
+            }
+        }
+        if self.format.events_from_other_layer {
+            return;
+        }
 
         // we print the event is we print by chronological order, or if the event is at the root
-        match (self.format.wrapped, ctx.current_span().id().is_some()) {
-            (false, _) | (true, false) => {
-                let buf = evt_record.serialize(&self.format);
-                eprintln!("{}", std::str::from_utf8(&buf).unwrap());
+        let wrapped = self.format.wrapped && !self.format.output_format.is_streamed();
+        match (wrapped, ctx.event_span(event)) {
+            (false, _) | (true, None) => {
+                // NB: an event outside of a span is only in focus by itself
+                let focus = self.format.focus.as_ref().filter(|_| self.format.wrapped);
+                if focus.is_some_and(|focus| !evt_record.in_focus(focus.0.as_ref())) {
+                    return;
+                }
+                self.write_event_record(&evt_record);
             }
-            _ => {
+            (true, Some(span_ref)) => {
                 // NB: push the events to the span record if everything is printed at the end
-                let curr_span = ctx.current_span();
-                let parent_span_id = curr_span.id().unwrap();
-                let span_ref = ctx.span(parent_span_id).expect("span not found");
                 let mut extensions = span_ref.extensions_mut();
                 let span_record = extensions
                     .get_mut::<SpanExtRecord>()
@@ -507,28 +3847,518 @@ where
 }
 
 impl PrettyConsoleLayer {
+    /// Formats an event with the layer options, and writes it to a writer
+    ///
+    /// This is the formatting entry point for custom layers which want to reuse this layer
+    /// formatting, with a different dispatch.
+    pub fn write_event<S>(
+        &self,
+        event: &tracing::Event<'_>,
+        ctx: &tracing_subscriber::layer::Context<'_, S>,
+        buf: &mut impl Write,
+    ) -> std::io::Result<()>
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        let evt_record = self.event_record(event, ctx);
+        let record_buf = evt_record.try_serialize(&self.format)?;
+        let result = buf
+            .write_all(&record_buf)
+            .and_then(|_| buf.write_all(b"\n"));
+        recycle_buffer(record_buf);
+        result
+    }
+
+    /// Builds the record of an event
+    fn event_record<S>(
+        &self,
+        event: &tracing::Event<'_>,
+        ctx: &tracing_subscriber::layer::Context<'_, S>,
+    ) -> EventRecord
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        let created = Instant::now();
+        let visitor =
+            EventVisitor::record_event_with_message_field(event, self.format.message_field);
+
+        let mut meta_fields = visitor.meta_fields();
+        meta_fields.redact(&self.format.redacted_fields);
+        let span_ref = ctx.event_span(event);
+
+        let mut inherited_fields = Fields::default();
+        if let Some(span_ref) = span_ref
+            .as_ref()
+            .filter(|_| self.format.inherit_span_fields)
+        {
+            for span in span_ref.scope().from_root() {
+                if let Some(record) = span.extensions().get::<SpanExtRecord>() {
+                    inherited_fields.merge(record.attrs.clone());
+                }
+            }
+        }
+        let mut context_fields: Vec<(String, String)> = inherited_fields
+            .iter()
+            .filter(|(k, _)| meta_fields.get(k).is_none())
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+
+        if let Some(provider) = &self.format.context_fields {
+            let provided_fields = (provider.0)()
+                .into_iter()
+                .filter(|(k, _)| meta_fields.get(k).is_none() && inherited_fields.get(k).is_none())
+                .map(
+                    |(k, v)| match self.format.redacted_fields.iter().any(|name| *name == k) {
+                        true => (k, "***".to_string()),
+                        false => (k, v),
+                    },
+                );
+            context_fields.extend(provided_fields);
+        }
+
+        let ancestors: Vec<(u64, &'static str)> = span_ref
+            .as_ref()
+            .map(|span_ref| {
+                span_ref
+                    .scope()
+                    .from_root()
+                    .map(|s| (s.id().into_u64(), s.name()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        #[cfg(feature = "serde")]
+        let span_fields = match (&span_ref, self.format.output_format) {
+            (Some(span_ref), OutputFormat::Json) => span_ref
+                .extensions()
+                .get::<SpanExtRecord>()
+                .map(|record| record.attrs.clone())
+                .unwrap_or_default(),
+            _ => Fields::default(),
+        };
+        // NB: an event with an explicit parent may be emitted while its span is not entered
+        let late = self.format.warn_late_events
+            && span_ref.as_ref().is_some_and(|span_ref| {
+                span_ref
+                    .extensions()
+                    .get::<SpanExtRecord>()
+                    .is_some_and(|record| record.active == 0)
+            });
+
+        EventRecord {
+            seq: self.next_seq(),
+            uptime: created.saturating_duration_since(self.started),
+            timestamp: time::OffsetDateTime::now_utc(),
+            level: *event.metadata().level(),
+            target: event.metadata().target().to_string(),
+            file: event.metadata().file().unwrap_or("").to_string(),
+            line: event.metadata().line().unwrap_or(0),
+            message: visitor.message().to_string(),
+            meta_fields,
+            span_target: span_ref
+                .as_ref()
+                .map(|span_ref| span_ref.metadata().target().to_string()),
+            #[cfg(feature = "serde")]
+            span_fields,
+            context_fields,
+            span: ancestors
+                .last()
+                .map(|(id, name)| (ancestors.len(), *id, name.to_string())),
+            ancestors,
+            panicking: self.format.mark_panics && std::thread::panicking(),
+            late,
+            task_id: self.format.show_task_id.then(current_task_id).flatten(),
+            thread: self.format.show_thread.then(current_thread),
+            delta: self.format.show_delta.then(|| event_delta(created)),
+        }
+    }
+
+    /// Returns a handle to the layer
+    pub fn handle(&self) -> PrettyConsoleHandle {
+        PrettyConsoleHandle {
+            state: self.state.clone(),
+        }
+    }
+
+    /// Returns the open spans, oldest first
+    pub fn open_spans(&self) -> Vec<OpenSpanInfo> {
+        self.handle().open_spans()
+    }
+
+    /// Returns a guard printing the summaries when dropped
+    pub fn summary_guard(&self) -> SummaryGuard {
+        self.handle().summary_guard()
+    }
+
+    /// Checks if a span or an event is printed, given its metadata
+    ///
+    /// NB: the filtering is done within the layer (and not with [tracing_subscriber::Layer::enabled]),
+    /// to not disable the records for the other layers.
+    fn is_enabled(&self, metadata: &tracing::Metadata<'_>) -> bool {
+        let target = metadata.target();
+        let level_enabled = self
+            .format
+            .max_level
+            .is_none_or(|max_level| *metadata.level() <= max_level);
+        let target_allowed = self
+            .format
+            .target_allowlist
+            .as_ref()
+            .is_none_or(|prefixes| prefixes.iter().any(|p| target.starts_with(p.as_str())));
+        let target_excluded = self
+            .format
+            .target_denylist
+            .iter()
+            .any(|p| target.starts_with(p.as_str()));
+        level_enabled && target_allowed && !target_excluded
+    }
+
+    /// Decides if a new span is sampled
+    fn sample_span(&self) -> bool {
+        let rate = self.format.span_sample_rate;
+        if rate >= 1.0 {
+            return true;
+        }
+        let n = self.state.sampling_count.fetch_add(1, Ordering::Relaxed) as f64;
+        let sampled = ((n + 1.0) * rate).floor() > (n * rate).floor();
+        if !sampled {
+            self.state.sampling_skipped.fetch_add(1, Ordering::Relaxed);
+        }
+        sampled
+    }
+
+    /// Prints the number of spans skipped by sampling since the last call
+    fn print_sampling_summary(&self) {
+        let skipped = self.state.sampling_skipped.swap(0, Ordering::Relaxed);
+        if skipped > 0 {
+            let summary = sampling_line(skipped, self.format.colorizes());
+            self.write_record(RecordKind::Span, summary.as_bytes());
+        }
+    }
+
+    /// Prints the root separator, if a root span tree has been printed before
+    fn print_root_separator(&self) {
+        let printed = self.state.root_printed.swap(true, Ordering::Relaxed);
+        if let (true, Some(separator)) = (printed, &self.format.root_separator) {
+            let separator = self.format.style(separator, |t| t.dimmed());
+            self.write_record(RecordKind::Span, separator.as_bytes());
+        }
+    }
+
+    /// Returns a legend explaining the format, for the current options
+    pub fn legend_text(&self) -> String {
+        let opts = &self.format;
+        let mut lines = vec![opts.style("Legend:", |t| t.bold())];
+
+        if !opts.events_only {
+            let name = opts.bracketed_name("name");
+            let (entry, exit) = if opts.wrapped {
+                (name.clone(), format!("!{name}"))
+            } else {
+                (
+                    format!("{:w$}{name}", opts.span_enter_marker, w = opts.indent),
+                    format!("{:w$}!{name}", opts.span_exit_marker, w = opts.indent),
+                )
+            };
+            let duration = format_duration(Duration::from_micros(123), opts.duration_human);
+            lines.push(format!(
+                "  {}  span entry",
+                opts.style(&entry, |t| t.magenta())
+            ));
+            lines.push(format!(
+                "  {} {}  span exit, with the span duration (us = microseconds)",
+                opts.style(&exit, |t| t.magenta()),
+                opts.style(&duration, |t| t.dimmed())
+            ));
+        }
+
+        let levels = [
+            Level::TRACE,
+            Level::DEBUG,
+            Level::INFO,
+            Level::WARN,
+            Level::ERROR,
+        ]
+        .map(|level| {
+            let style = &opts.level_style;
+            opts.style(style.label(level), |t| style.paint(t, level))
+        });
+        lines.push(format!("  {}  event levels", levels.join(" ")));
+
+        let mut fields = vec![];
+        if opts.shows_time() {
+            if opts.timestamp_mode == TimestampMode::Uptime {
+                fields.push("time: elapsed since the start (seconds)");
+            } else if opts.time_epoch.is_some() {
+                fields.push("time: offset from the epoch (seconds)");
+            } else {
+                fields.push(if opts.use_local_time {
+                    "time: timestamp (local time)"
+                } else {
+                    "time: timestamp (UTC)"
+                });
+            }
+        }
+        if opts.show_span_info {
+            fields.push("span.id / span.name: parent span");
+        }
+        if opts.show_target {
+            fields.push("target: module path");
+        }
+        if opts.show_file_info {
+            fields.push("file: source file and line");
+        }
+        fields.push("key=value: span attributes / event fields");
+        let separator = if opts.is_oneline() {
+            "same line"
+        } else {
+            "next lines"
+        };
+        lines.push(format!("  fields ({separator}):"));
+        for field in fields {
+            lines.push(format!("    {}", opts.style(field, |t| t.dimmed())));
+        }
+
+        lines.join("\n")
+    }
+
+    /// Prints a legend explaining the format
+    pub fn print_legend(&self) {
+        self.write_record(RecordKind::Span, self.legend_text().as_bytes());
+    }
+
+    /// Serializes an event, and writes it (serialization errors are reported, not propagated)
+    fn write_event_record(&self, record: &EventRecord) {
+        self.write_tree_event_record(record, &[]);
+    }
+
+    /// Writes an event of a span tree, with the tree guides of its position
+    fn write_tree_event_record(&self, record: &EventRecord, guides: &[bool]) {
+        match record.try_serialize(&self.format) {
+            Ok(buf) if buf.is_empty() => {}
+            Ok(buf) => {
+                let buf = self.format.draw_tree_guides(buf, guides, true);
+                self.write_buffer(RecordKind::Event(&record.target), buf)
+            }
+            Err(err) => self.state.writers.lock().unwrap().report_error(err),
+        }
+    }
+
+    /// Writes a trace event (serialization errors are reported, not propagated)
+    #[cfg(feature = "serde")]
+    fn write_trace_event(&self, buf: std::io::Result<Vec<u8>>) {
+        match buf {
+            Ok(buf) => self.write_buffer(RecordKind::Span, buf),
+            Err(err) => self.state.writers.lock().unwrap().report_error(err),
+        }
+    }
+
+    /// Writes a serialized record, and recycles its buffer
+    fn write_buffer(&self, kind: RecordKind, buf: Vec<u8>) {
+        self.write_record(kind, &buf);
+        recycle_buffer(buf);
+    }
+
+    /// Writes a serialized record, followed by a new line
+    ///
+    /// Write errors are not propagated (they are reported once on stderr, except broken pipes).
+    fn write_record(&self, kind: RecordKind, buf: &[u8]) {
+        if let Some(build_info) = &self.format.build_info {
+            if self.format.output_format == OutputFormat::Pretty
+                && !self.state.banner_printed.swap(true, Ordering::Relaxed)
+            {
+                let fields = build_info
+                    .fields()
+                    .iter()
+                    .map(|(k, v)| format!("{}={v}", self.format.style(k, |t| t.italic())))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let banner = format!("{} {fields}", self.format.style("build:", |t| t.bold()));
+                self.write_record(kind, banner.as_bytes());
+            }
+        }
+        if self.format.legend
+            && self.format.output_format == OutputFormat::Pretty
+            && !self.state.legend_printed.swap(true, Ordering::Relaxed)
+        {
+            self.write_record(kind, self.legend_text().as_bytes());
+        }
+        if self.format.output_format == OutputFormat::Tsv
+            && !self.state.header_printed.swap(true, Ordering::Relaxed)
+        {
+            self.write_record(kind, tsv::header(&self.format).as_bytes());
+        }
+        #[cfg(feature = "serde")]
+        if self.format.output_format == OutputFormat::ChromeTrace
+            && !self.state.header_printed.swap(true, Ordering::Relaxed)
+        {
+            self.write_record(kind, chrome::TRACE_HEADER.as_bytes());
+        }
+
+        let stripped;
+        let buf = if self.format.ansi || !buf.contains(&0x1b) {
+            buf
+        } else {
+            stripped = strip_ansi(buf);
+            stripped.as_slice()
+        };
+        self.state
+            .writers
+            .lock()
+            .unwrap()
+            .write(kind, buf, self.format.flush_policy);
+    }
+
+    /// Checks if a root tree has the same structure as the previous one, and counts the repeats
+    ///
+    /// When the structure changes, the repeats of the previous tree are printed.
+    fn is_repeated_tree(&self, record: &SpanExtRecord) -> bool {
+        let mut hasher = DefaultHasher::new();
+        record.hash_structure(&mut hasher);
+        let hash = hasher.finish();
+
+        let mut last_tree = self.state.last_tree.lock().unwrap();
+        match &mut *last_tree {
+            Some((last_hash, repeats, _)) if *last_hash == hash => {
+                *repeats += 1;
+                true
+            }
+            _ => {
+                let previous = last_tree.replace((hash, 0, record.name));
+                drop(last_tree);
+                if let Some((_, repeats @ 1.., name)) = previous {
+                    self.write_record(
+                        RecordKind::Span,
+                        repeats_line(repeats, name, self.format.ansi).as_bytes(),
+                    );
+                }
+                false
+            }
+        }
+    }
+
+    /// Returns the next sequence number
+    fn next_seq(&self) -> u64 {
+        self.seq.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Outputs a closed root span, and its tree
+    fn output_root(&self, mut record: SpanExtRecord) {
+        if self.is_out_of_focus(&record) {
+            return;
+        }
+        if self.format.dedupe_trees && self.is_repeated_tree(&record) {
+            return;
+        }
+        if let Some(threshold) = self.format.collapse_faster_than {
+            record.collapse_fast_children(threshold, self.format.collapse_keep_level);
+        }
+        if self.format.hide_unentered_spans {
+            record.hide_unentered_children();
+        }
+        if record.sampled {
+            self.print_root_separator();
+        }
+        if self.format.hide_unentered_spans && record.entered.is_none() {
+            // NB: the items of a hidden root are output as roots
+            record.level_up();
+            let items = record.tree_items(
+                self.format.children_position,
+                self.format.group_events_by_level,
+            );
+            for item in items {
+                match item {
+                    TreeItem::Event(event) => self.write_event_record(event),
+                    TreeItem::Child(child) => self.output_root_tree(child, None, &mut vec![]),
+                }
+            }
+            return;
+        }
+        self.output_root_tree(&record, None, &mut vec![]);
+    }
+
+    /// Checks if a span tree is out of focus (if a focus is set)
+    fn is_out_of_focus(&self, record: &SpanExtRecord) -> bool {
+        self.format
+            .focus
+            .as_ref()
+            .is_some_and(|focus| !record.contains_focus(focus.0.as_ref()))
+    }
+
     /// Outputs a tree of spans from the root
-    fn output_root_tree(&self, record: &SpanExtRecord) {
-        // eprintln!("ENTER SPAN {}", record.id);
-        let buf = record.serialize_span_entry(&self.format);
-        if !buf.is_empty() {
-            eprintln!("{}", std::str::from_utf8(&buf).unwrap());
+    ///
+    /// `guides` holds the tree guides of the record position (see `draw_tree_guides`).
+    fn output_root_tree(
+        &self,
+        record: &SpanExtRecord,
+        parent: Option<&SpanExtRecord>,
+        guides: &mut Vec<bool>,
+    ) {
+        #[cfg(feature = "serde")]
+        if self.format.output_format == OutputFormat::Yaml {
+            if record.sampled && !self.format.events_only {
+                match record.serialize_yaml(&self.format) {
+                    Ok(buf) => self.write_record(RecordKind::Span, &buf),
+                    Err(err) => self.state.writers.lock().unwrap().report_error(err),
+                }
+            }
+            return;
+        }
+
+        if self.is_out_of_focus(record) {
+            return;
         }
 
-        for event in &record.events {
-            let buf = event.serialize(&self.format);
+        if self.format.collapse_successful && !self.format.events_only && !record.has_problems() {
+            let buf = record.serialize_span_summary(&self.format);
             if !buf.is_empty() {
-                println!("{}", std::str::from_utf8(&buf).unwrap());
+                let buf = self.format.draw_tree_guides(buf, guides, true);
+                self.write_buffer(RecordKind::Span, buf);
             }
+            return;
         }
 
-        for child in &record.children {
-            self.output_root_tree(child);
+        if self.format.collapse_empty_spans
+            && record.events.is_empty()
+            && record.children.is_empty()
+        {
+            let buf = record.serialize_span_collapsed(
+                &self.format,
+                parent.map(|p| &p.attrs),
+                parent.map(|p| p.duration()),
+            );
+            if !buf.is_empty() {
+                self.print_sampling_summary();
+                let buf = self.format.draw_tree_guides(buf, guides, true);
+                self.write_buffer(RecordKind::Span, buf);
+            }
+            return;
+        }
+
+        let buf = record.serialize_span_entry(&self.format, parent.map(|p| &p.attrs));
+        if !buf.is_empty() {
+            self.print_sampling_summary();
+            let buf = self.format.draw_tree_guides(buf, guides, true);
+            self.write_buffer(RecordKind::Span, buf);
+        }
+
+        let items = record.tree_items(
+            self.format.children_position,
+            self.format.group_events_by_level,
+        );
+        let count = items.len();
+        for (i, item) in items.into_iter().enumerate() {
+            guides.push(i + 1 < count);
+            match item {
+                TreeItem::Event(event) => self.write_tree_event_record(event, guides),
+                TreeItem::Child(child) => self.output_root_tree(child, Some(record), guides),
+            }
+            guides.pop();
         }
 
-        let buf = record.serialize_span_exit(&self.format);
+        let buf = record.serialize_span_exit(&self.format, parent.map(|p| p.duration()));
         if !buf.is_empty() {
-            eprintln!("{}", std::str::from_utf8(&buf).unwrap());
+            let buf = self.format.draw_tree_guides(buf, guides, false);
+            self.write_buffer(RecordKind::Span, buf);
         }
     }
 }
@@ -0,0 +1,97 @@
+//! Trace Event Format (chrome://tracing, Perfetto)
+//!
+//! The events are streamed as an unterminated JSON array, 1 object per line (followed by a comma),
+//! which the trace viewers accept.
+
+use std::{
+    cell::Cell,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::Instant,
+};
+
+use serde_json::{Map, Value};
+
+use super::{json::fields_object, EventRecord, SpanExtRecord};
+
+/// Opening of the array of trace events
+pub(super) const TRACE_HEADER: &str = "[";
+
+/// Phase of a span trace event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Phase {
+    /// Span entry (`B`)
+    Begin,
+    /// Span exit (`E`)
+    End,
+}
+
+/// Returns the reference instant of the timestamps (the first trace event)
+fn epoch() -> Instant {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    *EPOCH.get_or_init(Instant::now)
+}
+
+/// Returns the ID of the current thread (small integers, in the order the threads are traced)
+fn thread_id() -> u64 {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    thread_local! {
+        static ID: Cell<u64> = const { Cell::new(0) };
+    }
+    ID.with(|id| {
+        if id.get() == 0 {
+            id.set(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+        }
+        id.get()
+    })
+}
+
+/// Serializes a trace event, with the common keys (`ph`, `pid`, `tid`, and `ts` in microseconds)
+fn trace_event(mut object: Map<String, Value>, phase: &str) -> std::io::Result<Vec<u8>> {
+    let ts = epoch().elapsed().as_nanos() as f64 / 1000.0;
+    object.insert("ph".into(), phase.into());
+    object.insert("pid".into(), std::process::id().into());
+    object.insert("tid".into(), thread_id().into());
+    object.insert("ts".into(), ts.into());
+    let mut buf = serde_json::to_vec(&object)?;
+    buf.push(b',');
+    Ok(buf)
+}
+
+impl SpanExtRecord {
+    /// Serializes a span entry or exit (trace event)
+    ///
+    /// The span attributes are the `args` of the entry.
+    pub(super) fn serialize_trace_event(&self, phase: Phase) -> std::io::Result<Vec<u8>> {
+        let mut object = Map::new();
+        object.insert("name".into(), self.name.into());
+        object.insert("cat".into(), self.target.as_str().into());
+        match phase {
+            Phase::Begin => {
+                object.insert("args".into(), fields_object(&self.attrs).into());
+                trace_event(object, "B")
+            }
+            Phase::End => trace_event(object, "E"),
+        }
+    }
+}
+
+impl EventRecord {
+    /// Serializes an event (instant trace event, on the thread track)
+    pub(super) fn serialize_trace_event(&self) -> std::io::Result<Vec<u8>> {
+        let mut args = fields_object(&self.meta_fields);
+        args.insert("level".into(), self.level.as_str().into());
+        for (k, v) in &self.context_fields {
+            args.insert(k.clone(), v.as_str().into());
+        }
+
+        let mut object = Map::new();
+        object.insert("name".into(), self.message.as_str().into());
+        object.insert("cat".into(), self.target.as_str().into());
+        object.insert("s".into(), "t".into());
+        object.insert("args".into(), args.into());
+        trace_event(object, "i")
+    }
+}
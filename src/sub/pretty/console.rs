@@ -0,0 +1,28 @@
+//! Windows console support
+
+use std::sync::Once;
+
+use windows_sys::Win32::System::Console::{
+    GetConsoleMode, GetStdHandle, SetConsoleMode, ENABLE_VIRTUAL_TERMINAL_PROCESSING,
+    STD_ERROR_HANDLE, STD_OUTPUT_HANDLE,
+};
+
+/// Enables the virtual terminal processing of the console (once), so that ANSI escape codes are rendered
+///
+/// Handles which are not attached to a console (e.g. redirected to a file) are left untouched.
+pub(super) fn enable_virtual_terminal() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        for std_handle in [STD_OUTPUT_HANDLE, STD_ERROR_HANDLE] {
+            // SAFETY: the handle is only used for console mode calls, which fail gracefully on
+            // invalid or non-console handles
+            unsafe {
+                let handle = GetStdHandle(std_handle);
+                let mut mode = 0;
+                if GetConsoleMode(handle, &mut mode) != 0 {
+                    SetConsoleMode(handle, mode | ENABLE_VIRTUAL_TERMINAL_PROCESSING);
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,71 @@
+//! JSON lines format
+
+use serde_json::{Map, Number, Value};
+use time::format_description::well_known::Rfc3339;
+
+use super::{EventRecord, Fields, PrettyFormatOptions};
+
+/// Returns an object with the fields (numbers are kept as JSON numbers)
+pub(super) fn fields_object(fields: &Fields) -> Map<String, Value> {
+    fields
+        .iter()
+        .map(|(k, v)| {
+            // NB: non-finite numbers are kept as strings
+            let value = fields
+                .number(k)
+                .and_then(|_| v.parse::<Number>().ok())
+                .map(Value::Number)
+                .unwrap_or_else(|| Value::String(v.into_owned()));
+            (k.to_string(), value)
+        })
+        .collect()
+}
+
+impl EventRecord {
+    /// Serializes an event (JSON format, 1 object per line)
+    ///
+    /// The keys are `timestamp`, `level`, `target`, `file`, `line`, `message`, `span` (the event
+    /// span `id`, `name` and `fields`, or `null`), and `fields`.
+    pub(super) fn serialize_json(&self, opts: &PrettyFormatOptions) -> std::io::Result<Vec<u8>> {
+        let mut object = Map::new();
+        let timestamp = self
+            .timestamp
+            .format(&Rfc3339)
+            .map_err(std::io::Error::other)?;
+        object.insert("timestamp".into(), timestamp.into());
+        object.insert("level".into(), self.level.as_str().into());
+        object.insert("target".into(), self.target.as_str().into());
+        object.insert("file".into(), opts.file_path(&self.file).into());
+        object.insert("line".into(), self.line.into());
+        object.insert("message".into(), self.message.as_str().into());
+
+        let span = match &self.span {
+            Some((_, id, name)) => {
+                let mut span = Map::new();
+                span.insert("id".into(), (*id).into());
+                span.insert("name".into(), name.as_str().into());
+                span.insert("fields".into(), fields_object(&self.span_fields).into());
+                Value::Object(span)
+            }
+            None => Value::Null,
+        };
+        object.insert("span".into(), span);
+
+        let mut fields = fields_object(&self.meta_fields);
+        for (k, v) in &self.context_fields {
+            fields.insert(k.clone(), v.as_str().into());
+        }
+        object.insert("fields".into(), fields.into());
+
+        if let Some(build_info) = &opts.build_info {
+            let build = build_info
+                .fields()
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.into()))
+                .collect::<Map<_, _>>();
+            object.insert("build".into(), build.into());
+        }
+
+        Ok(serde_json::to_vec(&object)?)
+    }
+}
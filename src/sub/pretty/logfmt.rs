@@ -0,0 +1,73 @@
+//! Logfmt format
+
+use std::{borrow::Cow, io::Write};
+
+use time::format_description::well_known::Rfc3339;
+
+use super::{take_buffer, EventRecord, PrettyFormatOptions};
+
+/// Quotes a value if it is empty or contains spaces, `=` or quotes (escaping quotes and backslashes)
+fn quote_value(value: &str) -> Cow<'_, str> {
+    let needs_quotes = value.is_empty()
+        || value
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '=' | '"' | '\\'));
+    if !needs_quotes {
+        return Cow::Borrowed(value);
+    }
+
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    Cow::Owned(quoted)
+}
+
+impl EventRecord {
+    /// Serializes an event (logfmt format)
+    ///
+    /// `ts=... level=info msg="..." target=... span=... key=value ...`
+    pub(super) fn serialize_logfmt(&self, opts: &PrettyFormatOptions) -> std::io::Result<Vec<u8>> {
+        let mut buf = take_buffer();
+
+        let timestamp = self
+            .timestamp
+            .format(&Rfc3339)
+            .map_err(std::io::Error::other)?;
+        write!(
+            buf,
+            "ts={timestamp} level={} msg={} target={}",
+            self.level.as_str().to_lowercase(),
+            quote_value(&self.message),
+            quote_value(&self.target)
+        )?;
+        if let Some((_, _, name)) = &self.span {
+            write!(buf, " span={}", quote_value(name))?;
+        }
+
+        let context_fields = self
+            .context_fields
+            .iter()
+            .map(|(k, v)| (k.as_str(), Cow::Borrowed(v.as_str())));
+        let fields = self
+            .meta_fields
+            .iter()
+            .map(|(k, v)| (k as &str, v))
+            .chain(context_fields)
+            .filter(|(_, v)| !opts.hides_value(v));
+        for (k, v) in fields {
+            write!(buf, " {}{}={}", opts.field_prefix, k, quote_value(&v))?;
+        }
+
+        Ok(buf)
+    }
+}
@@ -0,0 +1,359 @@
+//! Capture of the spans and events to a file, and replay
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::Level;
+
+use super::{EventRecord, Fields, PrettyConsoleLayer, SpanExtRecord};
+
+/// A captured span, recorded when the span is closed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapturedSpan {
+    /// Sequence number
+    pub seq: u64,
+    /// Span ID
+    pub id: u64,
+    /// Parent span ID
+    pub parent: Option<u64>,
+    /// IDs of the span ancestors, from the root to the parent
+    #[serde(default)]
+    pub ancestor_ids: Vec<u64>,
+    /// Level within the tree
+    pub tree_level: usize,
+    /// Span name
+    pub name: String,
+    /// Span target
+    pub target: String,
+    /// File
+    pub file: String,
+    /// Line
+    pub line: u32,
+    /// Span attributes (final values)
+    pub attrs: Vec<(String, String)>,
+    /// Span duration (in microseconds)
+    pub duration_us: u64,
+    /// Time spent entered (in microseconds)
+    #[serde(default)]
+    pub busy_us: u64,
+    /// Creation time (in nanoseconds since the Unix epoch)
+    #[serde(default)]
+    pub timestamp_ns: i64,
+    /// Creation time, since the layer was built (in nanoseconds)
+    #[serde(default)]
+    pub uptime_ns: u64,
+    /// Delay between the creation and the first enter (in nanoseconds), if the span was entered
+    #[serde(default)]
+    pub entered_ns: Option<u64>,
+}
+
+/// A captured event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapturedEvent {
+    /// Sequence number
+    pub seq: u64,
+    /// Level (eg `INFO`)
+    pub level: String,
+    /// Target
+    pub target: String,
+    /// File
+    pub file: String,
+    /// Line
+    pub line: u32,
+    /// Message
+    pub message: String,
+    /// Fields (inc. context fields)
+    pub fields: Vec<(String, String)>,
+    /// Span ancestors, from the root to the event span (id, name)
+    pub spans: Vec<(u64, String)>,
+    /// Target of the event span
+    pub span_target: Option<String>,
+    /// The event was emitted while the thread was panicking
+    pub panicking: bool,
+    /// The event was emitted on a span which was not entered
+    #[serde(default)]
+    pub late: bool,
+    /// Time of the event (in nanoseconds since the Unix epoch)
+    #[serde(default)]
+    pub timestamp_ns: i64,
+    /// Time of the event, since the layer was built (in nanoseconds)
+    #[serde(default)]
+    pub uptime_ns: u64,
+}
+
+/// A captured record (1 JSON object per line)
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub(super) enum Captured {
+    Span(CapturedSpan),
+    Event(CapturedEvent),
+}
+
+impl CapturedSpan {
+    /// Captures a span record
+    pub(super) fn from_record(record: &SpanExtRecord, parent: Option<u64>) -> Self {
+        Self {
+            seq: record.seq,
+            id: record.id,
+            parent,
+            ancestor_ids: record.ancestor_ids.clone(),
+            tree_level: record.tree_level,
+            name: record.name.to_string(),
+            target: record.target.clone(),
+            file: record.file.clone(),
+            line: record.line,
+            attrs: record
+                .attrs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.into_owned()))
+                .collect(),
+            duration_us: record.duration().as_micros() as u64,
+            busy_us: record.busy().as_micros() as u64,
+            timestamp_ns: timestamp_ns(record.timestamp),
+            uptime_ns: record.uptime.as_nanos() as u64,
+            entered_ns: record.schedule_delay().map(|delay| delay.as_nanos() as u64),
+        }
+    }
+}
+
+impl CapturedEvent {
+    /// Captures an event record
+    pub(super) fn from_record(record: &EventRecord) -> Self {
+        let fields = record
+            .meta_fields
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.into_owned()))
+            .chain(record.context_fields.iter().cloned())
+            .collect();
+        Self {
+            seq: record.seq,
+            level: record.level.to_string(),
+            target: record.target.clone(),
+            file: record.file.clone(),
+            line: record.line,
+            message: record.message.clone(),
+            fields,
+            spans: record
+                .ancestors
+                .iter()
+                .map(|(id, name)| (*id, name.to_string()))
+                .collect(),
+            span_target: record.span_target.clone(),
+            panicking: record.panicking,
+            late: record.late,
+            timestamp_ns: timestamp_ns(record.timestamp),
+            uptime_ns: record.uptime.as_nanos() as u64,
+        }
+    }
+}
+
+/// Writes a captured record to the capture file
+pub(super) fn write_captured(file: &mut File, captured: &Captured) -> io::Result<()> {
+    let mut line = serde_json::to_vec(captured).map_err(io::Error::other)?;
+    line.push(b'\n');
+    file.write_all(&line)
+}
+
+/// Interned names (span names and field names)
+///
+/// The records hold static names, so each distinct name is leaked once.
+#[derive(Default)]
+struct Names(HashMap<String, &'static str>);
+
+impl Names {
+    /// Returns the static name
+    fn get(&mut self, name: String) -> &'static str {
+        if let Some(name) = self.0.get(&name) {
+            return name;
+        }
+        let leaked: &'static str = Box::leak(name.clone().into_boxed_str());
+        self.0.insert(name, leaked);
+        leaked
+    }
+}
+
+/// A writer shared by the events and spans
+struct SharedWriter<W>(Arc<Mutex<W>>);
+
+impl<W: Write> Write for SharedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+impl PrettyConsoleLayer {
+    /// Sets a file to which all the spans and events are captured, as JSON lines
+    ///
+    /// The file is created (or truncated). It can be rendered later, with any options, with
+    /// [replay](Self::replay). Spans are captured when closed.
+    pub fn record_to(self, path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::create(path)?;
+        *self.state.recorder.lock().unwrap() = Some(file);
+        Ok(self)
+    }
+
+    /// Renders a capture file (see [record_to](Self::record_to)) with the layer options, to a writer
+    ///
+    /// The span trees are rendered when their root is closed. Spans which were not closed during the
+    /// capture are not rendered.
+    ///
+    /// ```no_run
+    /// use tracing_ext::sub::PrettyConsoleLayer;
+    ///
+    /// PrettyConsoleLayer::default()
+    ///     .wrapped(true)
+    ///     .replay("trace.jsonl", std::io::stdout())
+    ///     .unwrap();
+    /// ```
+    pub fn replay(
+        self,
+        path: impl AsRef<Path>,
+        writer: impl Write + Send + 'static,
+    ) -> io::Result<()> {
+        let writer = Arc::new(Mutex::new(writer));
+        let handle = self.handle();
+        handle.set_event_writer(SharedWriter(writer.clone()));
+        handle.set_span_writer(SharedWriter(writer));
+
+        let mut names = Names::default();
+        let mut events: HashMap<u64, Vec<EventRecord>> = HashMap::new();
+        let mut children: HashMap<u64, Vec<SpanExtRecord>> = HashMap::new();
+
+        let reader = BufReader::new(File::open(path)?);
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let captured: Captured = serde_json::from_str(&line)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            match captured {
+                Captured::Event(event) => {
+                    let record = event_record(event, &mut names)?;
+                    match record.ancestors.last() {
+                        Some((span_id, _)) => events.entry(*span_id).or_default().push(record),
+                        None => {
+                            self.write_event_record(&record);
+                        }
+                    }
+                }
+                Captured::Span(span) => {
+                    let parent = span.parent;
+                    let mut record = span_record(span, &mut names);
+                    record.events = events.remove(&record.id).unwrap_or_default();
+                    record.children = children.remove(&record.id).unwrap_or_default();
+                    record.event_count = record.tree_counts().0;
+                    match parent {
+                        Some(parent) => children.entry(parent).or_default().push(record),
+                        None => self.output_root(record),
+                    }
+                }
+            }
+        }
+
+        handle.flush();
+        Ok(())
+    }
+}
+
+/// Returns the record of a captured span
+fn span_record(span: CapturedSpan, names: &mut Names) -> SpanExtRecord {
+    let mut attrs = Fields::default();
+    for (k, v) in span.attrs {
+        attrs.insert(names.get(k), v);
+    }
+    let created = Instant::now();
+    SpanExtRecord {
+        tree_level: span.tree_level,
+        seq: span.seq,
+        id: span.id,
+        ancestor_ids: span.ancestor_ids,
+        task_id: None,
+        name: names.get(span.name),
+        target: span.target,
+        file: span.file,
+        line: span.line,
+        attrs,
+        created,
+        uptime: Duration::from_nanos(span.uptime_ns),
+        timestamp: timestamp(span.timestamp_ns),
+        entered: span
+            .entered_ns
+            .map(|delay| created + Duration::from_nanos(delay)),
+        active: 0,
+        last_entered: None,
+        busy: Duration::from_micros(span.busy_us),
+        children_busy: Duration::ZERO,
+        event_count: 0,
+        closed: Some(created + Duration::from_micros(span.duration_us)),
+        sampled: true,
+        lazy_attrs: true,
+        events: vec![],
+        children: vec![],
+    }
+}
+
+/// Returns the record of a captured event
+fn event_record(event: CapturedEvent, names: &mut Names) -> io::Result<EventRecord> {
+    let level: Level = event
+        .level
+        .parse()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let mut meta_fields = Fields::default();
+    for (k, v) in event.fields {
+        meta_fields.insert(names.get(k), v);
+    }
+    let ancestors: Vec<(u64, &'static str)> = event
+        .spans
+        .into_iter()
+        .map(|(id, name)| (id, names.get(name)))
+        .collect();
+    let span = ancestors
+        .last()
+        .map(|(id, name)| (ancestors.len(), *id, name.to_string()));
+    Ok(EventRecord {
+        seq: event.seq,
+        uptime: Duration::from_nanos(event.uptime_ns),
+        timestamp: timestamp(event.timestamp_ns),
+        level,
+        target: event.target,
+        file: event.file,
+        line: event.line,
+        message: event.message,
+        meta_fields,
+        span,
+        span_target: event.span_target,
+        span_fields: Fields::default(),
+        context_fields: vec![],
+        ancestors,
+        panicking: event.panicking,
+        late: event.late,
+        task_id: None,
+        thread: None,
+        delta: None,
+    })
+}
+
+/// Returns the nanoseconds since the Unix epoch
+///
+/// NB: `i64` (until year 2262), since the tagged records cannot hold an `i128`
+fn timestamp_ns(timestamp: time::OffsetDateTime) -> i64 {
+    timestamp.unix_timestamp_nanos() as i64
+}
+
+/// Returns the captured time (the Unix epoch if it is invalid)
+fn timestamp(nanos: i64) -> time::OffsetDateTime {
+    time::OffsetDateTime::from_unix_timestamp_nanos(nanos.into())
+        .unwrap_or(time::OffsetDateTime::UNIX_EPOCH)
+}
@@ -0,0 +1,170 @@
+//! RFC 5424 (syslog) format
+
+use std::{borrow::Cow, io::Write, sync::OnceLock};
+
+use time::format_description::well_known::Rfc3339;
+use tracing::Level;
+
+use super::{take_buffer, EventRecord, PrettyFormatOptions};
+
+/// Default syslog facility (user-level messages)
+pub(super) const SYSLOG_FACILITY_DEFAULT: u8 = 1;
+
+/// Enterprise number of the structured data IDs (reserved for documentation, see RFC 5612)
+const SD_ENTERPRISE_NUMBER: u32 = 32473;
+
+/// Max length of the HOSTNAME header field
+const HOSTNAME_MAX_LEN: usize = 255;
+
+/// Max length of the APP-NAME header field
+const APP_NAME_MAX_LEN: usize = 48;
+
+/// Max length of a structured data parameter name (SD-NAME)
+const SD_NAME_MAX_LEN: usize = 32;
+
+/// Returns the syslog severity of a level
+fn severity(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+/// Returns the hostname, determined once, or the nil value (`-`)
+///
+/// The `HOSTNAME` variable is usually not exported by the shells, so `/etc/hostname` is read
+/// otherwise.
+fn hostname() -> &'static str {
+    static HOSTNAME: OnceLock<String> = OnceLock::new();
+    HOSTNAME.get_or_init(|| {
+        let hostname = std::env::var("HOSTNAME")
+            .ok()
+            .filter(|h| !h.trim().is_empty())
+            .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+            .unwrap_or_default();
+        let hostname: String = hostname
+            .trim()
+            .chars()
+            .filter(|c| c.is_ascii_graphic())
+            .take(HOSTNAME_MAX_LEN)
+            .collect();
+        if hostname.is_empty() {
+            "-".to_string()
+        } else {
+            hostname
+        }
+    })
+}
+
+/// Returns the app name, or the nil value (`-`)
+fn app_name(opts: &PrettyFormatOptions) -> String {
+    let app_name = match &opts.syslog_app_name {
+        Some(app_name) => app_name.clone(),
+        None => std::env::current_exe()
+            .ok()
+            .and_then(|p| p.file_stem().map(|s| s.to_string_lossy().to_string()))
+            .unwrap_or_else(|| "-".to_string()),
+    };
+    app_name
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(APP_NAME_MAX_LEN)
+        .collect()
+}
+
+/// Returns a valid structured data parameter name (SD-NAME)
+///
+/// The characters which are not allowed (`=`, space, `]`, `"`, and non-printable characters) are
+/// replaced by `_`, and the name is truncated.
+fn sd_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '=' | ']' | '"' => '_',
+            c if c.is_ascii_graphic() => c,
+            _ => '_',
+        })
+        .take(SD_NAME_MAX_LEN)
+        .collect();
+    if name.is_empty() {
+        "_".to_string()
+    } else {
+        name
+    }
+}
+
+/// Escapes a structured data parameter value
+fn escape_param_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+impl EventRecord {
+    /// Serializes an event (syslog format)
+    ///
+    /// `<PRI>1 TIMESTAMP HOSTNAME APP-NAME PROCID MSGID [SD] MSG`
+    pub(super) fn serialize_syslog(&self, opts: &PrettyFormatOptions) -> std::io::Result<Vec<u8>> {
+        let mut buf = take_buffer();
+
+        let pri = opts.syslog_facility as u32 * 8 + severity(&self.level) as u32;
+        let timestamp = self
+            .timestamp
+            .format(&Rfc3339)
+            .map_err(std::io::Error::other)?;
+        write!(
+            buf,
+            "<{pri}>1 {timestamp} {} {} {} -",
+            hostname(),
+            app_name(opts),
+            std::process::id()
+        )?;
+
+        if self.meta_fields.is_empty()
+            && self.context_fields.is_empty()
+            && opts.build_info.is_none()
+        {
+            write!(buf, " -")?;
+        } else {
+            write!(buf, " ")?;
+        }
+
+        if !self.meta_fields.is_empty() || !self.context_fields.is_empty() {
+            write!(buf, "[fields@{SD_ENTERPRISE_NUMBER}")?;
+            let context_fields = self
+                .context_fields
+                .iter()
+                .map(|(k, v)| (k.as_str(), Cow::Borrowed(v.as_str())));
+            let fields = self
+                .meta_fields
+                .iter()
+                .map(|(k, v)| (k as &str, v))
+                .chain(context_fields)
+                .filter(|(_, v)| !opts.hides_value(v));
+            for (k, v) in fields {
+                let name = sd_name(&format!("{}{k}", opts.field_prefix));
+                write!(buf, " {name}=\"{}\"", escape_param_value(&v))?;
+            }
+            write!(buf, "]")?;
+        }
+
+        if let Some(build_info) = &opts.build_info {
+            write!(buf, "[build@{SD_ENTERPRISE_NUMBER}")?;
+            for (k, v) in build_info.fields() {
+                write!(buf, " {}=\"{}\"", sd_name(k), escape_param_value(v))?;
+            }
+            write!(buf, "]")?;
+        }
+
+        write!(buf, " {}", self.message)?;
+
+        Ok(buf)
+    }
+}
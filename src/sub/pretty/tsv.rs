@@ -0,0 +1,99 @@
+//! TSV (tab-separated values) format
+
+use std::{borrow::Cow, io::Write};
+
+use time::format_description::well_known::Rfc3339;
+
+use super::{take_buffer, EventRecord, PrettyFormatOptions};
+
+/// Header row
+const TSV_HEADER: &str = "timestamp\tlevel\ttarget\tmessage\tspan\tfields";
+
+/// Returns the header row
+///
+/// The build info columns (`version`, and `commit` if set) are appended after the fields.
+pub(super) fn header(opts: &PrettyFormatOptions) -> String {
+    let mut header = if opts.kind_marker {
+        format!("kind\t{TSV_HEADER}")
+    } else {
+        TSV_HEADER.to_string()
+    };
+    if let Some(build_info) = &opts.build_info {
+        for (k, _) in build_info.fields() {
+            header.push('\t');
+            header.push_str(k);
+        }
+    }
+    header
+}
+
+/// Escapes a value (backslash, tab, and line breaks)
+fn escape_value(value: &str) -> Cow<'_, str> {
+    if !value.contains(['\\', '\t', '\n', '\r']) {
+        return Cow::Borrowed(value);
+    }
+    let mut escaped = String::with_capacity(value.len() + 2);
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+impl EventRecord {
+    /// Serializes an event (TSV format)
+    ///
+    /// The fields are collapsed into a single column, as `key=value` pairs separated by spaces.
+    pub(super) fn serialize_tsv(&self, opts: &PrettyFormatOptions) -> std::io::Result<Vec<u8>> {
+        let mut buf = take_buffer();
+        if opts.kind_marker {
+            write!(buf, "E\t")?;
+        }
+
+        let timestamp = self
+            .timestamp
+            .format(&Rfc3339)
+            .map_err(std::io::Error::other)?;
+        let span = self
+            .span
+            .as_ref()
+            .map(|(_, _, name)| name.as_str())
+            .unwrap_or("");
+        let context_fields = self
+            .context_fields
+            .iter()
+            .map(|(k, v)| (k.as_str(), Cow::Borrowed(v.as_str())));
+        let fields = self
+            .meta_fields
+            .iter()
+            .map(|(k, v)| (k as &str, v))
+            .chain(context_fields)
+            .filter(|(_, v)| !opts.hides_value(v))
+            .map(|(k, v)| format!("{}{}={}", opts.field_prefix, k, v))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        write!(
+            buf,
+            "{timestamp}\t{}\t{}\t{}\t{}\t{}",
+            self.level,
+            escape_value(&self.target),
+            escape_value(&self.message),
+            escape_value(span),
+            escape_value(&fields)
+        )?;
+
+        if let Some(build_info) = &opts.build_info {
+            for (_, v) in build_info.fields() {
+                write!(buf, "\t{}", escape_value(v))?;
+            }
+        }
+
+        Ok(buf)
+    }
+}
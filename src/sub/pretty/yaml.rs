@@ -0,0 +1,112 @@
+//! YAML format
+
+use serde_yaml::{Mapping, Value};
+
+use super::{EventRecord, PrettyFormatOptions, SpanExtRecord};
+
+/// Serializes a YAML document (with its leading `---` marker, and without the trailing newline)
+///
+/// The build info is added as a top-level `build` entry.
+fn to_document(mut value: Value, opts: &PrettyFormatOptions) -> std::io::Result<Vec<u8>> {
+    if let (Some(build_info), Value::Mapping(map)) = (&opts.build_info, &mut value) {
+        let build = build_info
+            .fields()
+            .into_iter()
+            .map(|(k, v)| (k.into(), v.into()))
+            .collect::<Mapping>();
+        map.insert("build".into(), Value::Mapping(build));
+    }
+    let yaml = serde_yaml::to_string(&value).map_err(std::io::Error::other)?;
+    Ok(format!("---\n{}", yaml.trim_end()).into_bytes())
+}
+
+/// Returns a mapping with a single entry
+fn single_entry(key: &str, value: Value) -> Value {
+    let mut map = Mapping::new();
+    map.insert(key.into(), value);
+    Value::Mapping(map)
+}
+
+impl SpanExtRecord {
+    /// Serializes a span tree (YAML format)
+    ///
+    /// The span name is the key of the document, and its children are nested the same way.
+    pub(super) fn serialize_yaml(&self, opts: &PrettyFormatOptions) -> std::io::Result<Vec<u8>> {
+        to_document(single_entry(self.name, self.yaml_value(opts)), opts)
+    }
+
+    /// Returns the YAML value of a span (attributes, duration, events and children)
+    fn yaml_value(&self, opts: &PrettyFormatOptions) -> Value {
+        let mut map = Mapping::new();
+        if opts.show_span_info {
+            map.insert("id".into(), self.id.into());
+        }
+        if opts.show_target {
+            map.insert("target".into(), self.target.as_str().into());
+        }
+        if let Some(file_info) = opts.file_info(&self.file, self.line) {
+            map.insert("file".into(), file_info.into());
+        }
+        if !self.attrs.is_empty() {
+            let attrs = self
+                .attrs
+                .iter()
+                .map(|(k, v)| (k.into(), v.as_ref().into()))
+                .collect::<Mapping>();
+            map.insert("attrs".into(), Value::Mapping(attrs));
+        }
+        map.insert(
+            "duration_us".into(),
+            (self.duration().as_micros() as u64).into(),
+        );
+        if !self.events.is_empty() {
+            let events = self.events.iter().map(|e| e.yaml_value(opts)).collect();
+            map.insert("events".into(), Value::Sequence(events));
+        }
+        if !self.children.is_empty() {
+            let children = self
+                .children
+                .iter()
+                .map(|c| single_entry(c.name, c.yaml_value(opts)))
+                .collect();
+            map.insert("children".into(), Value::Sequence(children));
+        }
+        Value::Mapping(map)
+    }
+}
+
+impl EventRecord {
+    /// Serializes an event outside of a span (YAML format)
+    pub(super) fn serialize_yaml(&self, opts: &PrettyFormatOptions) -> std::io::Result<Vec<u8>> {
+        to_document(single_entry("event", self.yaml_value(opts)), opts)
+    }
+
+    /// Returns the YAML value of an event
+    fn yaml_value(&self, opts: &PrettyFormatOptions) -> Value {
+        let mut map = Mapping::new();
+        map.insert("level".into(), self.level.as_str().into());
+        map.insert("message".into(), self.message.as_str().into());
+        if opts.show_target {
+            map.insert("target".into(), self.target.as_str().into());
+        }
+        if let Some(file_info) = opts.file_info(&self.file, self.line) {
+            map.insert("file".into(), file_info.into());
+        }
+        let fields = self
+            .meta_fields
+            .iter()
+            .map(|(k, v)| (k, v.into_owned()))
+            .chain(
+                self.context_fields
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), v.clone())),
+            )
+            .filter(|(_, v)| !opts.hides_value(v))
+            .map(|(k, v)| (format!("{}{}", opts.field_prefix, k).into(), v.into()))
+            .collect::<Mapping>();
+        if !fields.is_empty() {
+            map.insert("fields".into(), Value::Mapping(fields));
+        }
+        Value::Mapping(map)
+    }
+}
@@ -1,11 +1,18 @@
 //! Subscriber tests
 
-use std::sync::Once;
+use std::{
+    io::Write,
+    sync::{Arc, Mutex, Once},
+    time::Duration,
+};
 
 use tracing::{debug, info, warn};
 use tracing_subscriber::{prelude::*, EnvFilter};
 
-use super::pretty::PrettyConsoleLayer;
+use super::pretty::{
+    file_url, format_duration, strip_ansi, AttrPlacement, FlushPolicy, PrettyConsoleLayer,
+    TimestampMode,
+};
 
 /// Keep track of tests initialization
 static INIT: Once = Once::new();
@@ -80,3 +87,1367 @@ fn test_simple() {
     do_something(1, 2);
     info!("Test OK");
 }
+
+/// A writer capturing the output
+#[derive(Debug, Clone, Default)]
+struct TestWriter(Arc<Mutex<Vec<u8>>>);
+
+impl Write for TestWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl TestWriter {
+    /// Returns the captured output
+    fn output(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+/// Runs a closure with a layer, and returns the captured output (spans and events)
+fn capture(layer: PrettyConsoleLayer, f: impl FnOnce()) -> String {
+    let writer = TestWriter::default();
+    let handle = layer.handle();
+    handle.set_event_writer(writer.clone());
+    handle.set_span_writer(writer.clone());
+    let subscriber = tracing_subscriber::registry().with(layer);
+    tracing::subscriber::with_default(subscriber, f);
+    writer.output()
+}
+
+#[test]
+fn test_writer_swap() {
+    let layer = PrettyConsoleLayer::default();
+    let handle = layer.handle();
+    let writer_1 = TestWriter::default();
+    let writer_2 = TestWriter::default();
+    handle.set_event_writer(writer_1.clone());
+
+    let subscriber = tracing_subscriber::registry().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        info!("event 1");
+        assert!(handle.set_event_writer(writer_2.clone()).is_some());
+        info!("event 2");
+    });
+
+    assert!(writer_1.output().contains("event 1"));
+    assert!(!writer_1.output().contains("event 2"));
+    assert!(writer_2.output().contains("event 2"));
+}
+
+#[test]
+fn test_field_order() {
+    for _ in 0..10 {
+        let layer = PrettyConsoleLayer::default().show_time(false);
+        let output = capture(layer, || {
+            let _span = tracing::info_span!("span_1", user = "u", id = 1, path = "/").entered();
+            info!(user = "u", id = 1, path = "/", "event 1");
+        });
+        let positions =
+            |output: &str| ["user=", "id=", "path="].map(|field| output.find(field).unwrap());
+        let span_entry = &output[..output.find("event 1").unwrap()];
+        let event = &output[output.find("event 1").unwrap()..];
+        for positions in [positions(span_entry), positions(event)] {
+            assert!(positions[0] < positions[1] && positions[1] < positions[2]);
+        }
+    }
+}
+
+#[test]
+fn test_typed_fields() {
+    let layer = PrettyConsoleLayer::default();
+    let output = capture(layer, || {
+        let _span = tracing::info_span!("span_1", user = "alice", ok = true).entered();
+        info!(name = "bob", n = 42, x = 1.5, "event 1");
+    });
+    assert!(output.contains("user=alice"));
+    assert!(output.contains("ok=true"));
+    assert!(output.contains("name=bob"));
+    assert!(output.contains("n=42"));
+    assert!(output.contains("x=1.5"));
+}
+
+#[test]
+fn test_repeated_fields() {
+    let layer = PrettyConsoleLayer::default();
+    let output = capture(layer, || {
+        let span = tracing::info_span!("span", a = 1, a = 2);
+        let _entered = span.enter();
+        info!(x = 3, x = 4, "repeated");
+    });
+
+    assert!(output.contains("a=[1, 2]"));
+    assert!(output.contains("x=[3, 4]"));
+}
+
+#[test]
+fn test_concurrent_records() {
+    let layer = PrettyConsoleLayer::default()
+        .show_time(false)
+        .show_target(false)
+        .show_file_info(false);
+    let output = capture(layer, || {
+        let dispatch = tracing::dispatcher::get_default(|d| d.clone());
+        let threads: Vec<_> = (0..8)
+            .map(|t| {
+                let dispatch = dispatch.clone();
+                std::thread::spawn(move || {
+                    tracing::dispatcher::with_default(&dispatch, || {
+                        for i in 0..100 {
+                            info!(t, i, "event {t}-{i}");
+                        }
+                    });
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    });
+
+    // each record spans 3 lines (message, and 2 fields), which are never split
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines.len(), 8 * 100 * 3);
+    for record in lines.chunks(3) {
+        let (t, i) = record[0]
+            .rsplit_once(' ')
+            .unwrap()
+            .1
+            .split_once('-')
+            .unwrap();
+        assert_eq!(record[1].trim(), format!("t={t}"));
+        assert_eq!(record[2].trim(), format!("i={i}"));
+    }
+}
+
+#[test]
+fn test_level_alignment() {
+    for indent in [2, 6, 10] {
+        let layer = PrettyConsoleLayer::default()
+            .show_time(false)
+            .indent(indent);
+        let output = capture(layer, || {
+            info!("info message");
+            tracing::error!("error message");
+        });
+        let info_column = output
+            .lines()
+            .find_map(|line| line.find("info message"))
+            .unwrap();
+        let error_column = output
+            .lines()
+            .find_map(|line| line.find("error message"))
+            .unwrap();
+        assert_eq!(info_column, error_column);
+        assert_eq!(info_column, 6);
+    }
+}
+
+#[test]
+fn test_max_level() {
+    let layer = PrettyConsoleLayer::default().max_level(tracing::Level::WARN);
+    let output = capture(layer, || {
+        let _span = tracing::info_span!("info_span").entered();
+        let _warn_span = tracing::warn_span!("warn_span").entered();
+        info!("info event");
+        warn!("warn event");
+    });
+    assert!(!output.contains("info event"));
+    assert!(output.contains("warn event"));
+    assert!(!output.contains("info_span"));
+    assert!(output.contains("{warn_span}"));
+}
+
+#[test]
+fn test_target_filters() {
+    let layer = PrettyConsoleLayer::default().with_target_filter(vec!["myapp::".to_string()]);
+    let output = capture(layer, || {
+        info!(target: "myapp::foo", "from myapp");
+        info!(target: "hyper::conn", "from hyper");
+    });
+    assert!(output.contains("from myapp"));
+    assert!(!output.contains("from hyper"));
+
+    let layer = PrettyConsoleLayer::default().with_target_exclude(vec!["hyper::".to_string()]);
+    let output = capture(layer, || {
+        info!(target: "myapp::foo", "from myapp");
+        info!(target: "hyper::conn", "from hyper");
+    });
+    assert!(output.contains("from myapp"));
+    assert!(!output.contains("from hyper"));
+}
+
+#[test]
+fn test_show_thread() {
+    let layer = PrettyConsoleLayer::default().show_thread(true);
+    let output = capture(layer, || {
+        let dispatch = tracing::dispatcher::get_default(|d| d.clone());
+        std::thread::Builder::new()
+            .name("worker-1".to_string())
+            .spawn(move || tracing::dispatcher::with_default(&dispatch, || info!("from worker")))
+            .unwrap()
+            .join()
+            .unwrap();
+    });
+    assert!(output.contains("thread: worker-1 (ThreadId("));
+}
+
+#[test]
+fn test_field_value_max_len() {
+    let layer = PrettyConsoleLayer::default().field_value_max_len(Some(10));
+    let output = capture(layer, || {
+        let _span = tracing::info_span!("span", attr = "ééééééééééééé").entered();
+        info!(long = "abcdefghijklmnopqrstuvwxyz", short = "abc", "event");
+    });
+    assert!(output.contains("long=abcdefghij…"));
+    assert!(!output.contains("abcdefghijk"));
+    assert!(output.contains("short=abc"));
+    assert!(output.contains("attr=éééééééééé…"));
+}
+
+#[test]
+fn test_redact_fields() {
+    let layer = PrettyConsoleLayer::default().redact_fields(vec!["password", "token"]);
+    let output = capture(layer, || {
+        let span = tracing::info_span!("login", token = "abc123", user_id = tracing::field::Empty);
+        span.record("token", "def456");
+        let _span = span.entered();
+        info!(password = "hunter2", user = "bob", "login");
+    });
+    assert!(output.contains("password=***"));
+    assert!(output.contains("user=bob"));
+    assert!(output.contains("token=***"));
+    assert!(!output.contains("hunter2"));
+    assert!(!output.contains("abc123"));
+    assert!(!output.contains("def456"));
+}
+
+#[test]
+fn test_inherit_span_fields() {
+    let layer = PrettyConsoleLayer::default()
+        .inherit_span_fields(true)
+        .show_time(false)
+        .show_span_info(false)
+        .show_target(false)
+        .show_file_info(false);
+    let output = capture(layer, || {
+        let _outer = tracing::info_span!("request", request_id = 42, user = "bob").entered();
+        let _inner = tracing::info_span!("handler", user = "alice").entered();
+        info!(status = "ok", "handled");
+    });
+    let event = output
+        .split("INFO  handled")
+        .nth(1)
+        .expect("event not printed");
+    assert!(event.contains("status=ok"));
+    assert!(event.contains("request_id=42"));
+    assert!(event.contains("user=alice"));
+    assert!(!event.contains("user=bob"));
+}
+
+/// A writer which always fails
+struct FailingWriter(std::io::ErrorKind);
+
+impl std::io::Write for FailingWriter {
+    fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+        Err(self.0.into())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Err(self.0.into())
+    }
+}
+
+#[test]
+fn test_write_errors() {
+    for kind in [std::io::ErrorKind::BrokenPipe, std::io::ErrorKind::Other] {
+        let layer = PrettyConsoleLayer::default()
+            .with_writer(move || FailingWriter(kind))
+            .flush_policy(FlushPolicy::PerLine);
+        let handle = layer.handle();
+        handle.set_span_writer(FailingWriter(kind));
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("span").entered();
+            info!("event 1");
+            info!("event 2");
+        });
+        handle.flush();
+    }
+}
+
+#[test]
+fn test_syslog_sd_names() {
+    let layer = PrettyConsoleLayer::default().syslog(true);
+    let output = capture(layer, || {
+        info!(
+            "http path" = "/",
+            "a=b]\"c" = 1,
+            "a_very_long_field_name_over_the_limit" = 2,
+            "event"
+        );
+    });
+    assert!(output.contains(" http_path=\"/\""), "{output}");
+    assert!(output.contains(" a_b__c=\"1\""), "{output}");
+    assert!(
+        output.contains(" a_very_long_field_name_over_the_=\"2\""),
+        "{output}"
+    );
+}
+
+#[test]
+fn test_compact() {
+    let layer = PrettyConsoleLayer::default().compact(true);
+    let output = capture(layer, || {
+        info!(x = 1, "hi");
+    });
+    assert_eq!(strip_ansi(output.as_bytes()), b"INFO hi x=1\n");
+
+    // the spans are shown as 1 line, and disabling the compact mode restores the multiline mode
+    let record = || {
+        let _span = tracing::info_span!("span_1", a = 1).entered();
+    };
+    let output = capture(PrettyConsoleLayer::default().compact(true), record);
+    assert_eq!(output.lines().count(), 2);
+    let layer = PrettyConsoleLayer::default().compact(true).compact(false);
+    let output = capture(layer, record);
+    assert!(output.lines().count() > 2);
+}
+
+#[test]
+fn test_span_markers() {
+    let layer = PrettyConsoleLayer::default()
+        .span_enter_marker(">>")
+        .span_exit_marker("<<")
+        .span_name_brackets(("[".to_string(), "]".to_string()))
+        .show_span_info(false);
+    let output = capture(layer, || {
+        let _span = tracing::info_span!("my_span").entered();
+    });
+    assert!(output.contains(">>    [my_span]"));
+    assert!(output.contains("<<    ![my_span]"));
+    assert!(!output.contains("-->"));
+    assert!(!output.contains("{my_span}"));
+}
+
+#[test]
+fn test_level_style() {
+    let layer = PrettyConsoleLayer::default()
+        .level_label(tracing::Level::INFO, "info")
+        .level_color(tracing::Level::DEBUG, colored::Color::Cyan)
+        .compact(true);
+    let output = capture(layer, || {
+        info!("hi");
+        warn!("careful");
+    });
+    assert_eq!(output, "info hi\nWARN careful\n");
+}
+
+#[test]
+fn test_strip_file_prefix() {
+    let layer = PrettyConsoleLayer::default().strip_file_prefix("src/sub");
+    let output = capture(layer, || {
+        let _span = tracing::info_span!("span").entered();
+        info!("event");
+    });
+    assert_eq!(output.matches("file: tests.rs:").count(), 2);
+    assert!(!output.contains("src/sub/tests.rs"));
+}
+
+#[test]
+fn test_file_hyperlink() {
+    let layer = PrettyConsoleLayer::default()
+        .with_ansi(true)
+        .file_hyperlink(true);
+    let output = capture(layer, || info!("event"));
+    let cwd = std::env::current_dir().unwrap();
+    let link = format!("\x1b]8;;{}\x1b\\", file_url(&cwd.join("src/sub/tests.rs")));
+    let start = output.find(&link).expect("hyperlink not found");
+    let end = output.find("\x1b]8;;\x1b\\").expect("hyperlink not closed");
+    assert!(output[start + link.len()..end].starts_with("src/sub/tests.rs:"));
+
+    let layer = PrettyConsoleLayer::default()
+        .with_ansi(false)
+        .file_hyperlink(true);
+    let output = capture(layer, || info!("event"));
+    assert!(!output.contains("\x1b]8;;"));
+    assert!(output.contains("file: src/sub/tests.rs:"));
+
+    // the paths are percent-encoded
+    let url = file_url(std::path::Path::new("/my dir/a#b.rs"));
+    assert_eq!(url, "file:///my%20dir/a%23b.rs");
+}
+
+#[test]
+fn test_folded_stacks() {
+    let layer = PrettyConsoleLayer::default().folded_stacks(true);
+    let handle = layer.handle();
+    capture(layer, || {
+        let _root = tracing::info_span!("root").entered();
+        for _ in 0..2 {
+            let _child = tracing::info_span!("child").entered();
+            let _grandchild = tracing::info_span!("grandchild").entered();
+            std::thread::sleep(Duration::from_millis(2));
+        }
+    });
+
+    let mut folded = vec![];
+    handle.write_folded_stacks(&mut folded).unwrap();
+    let folded = String::from_utf8(folded).unwrap();
+    let stacks: Vec<(&str, u64)> = folded
+        .lines()
+        .map(|line| {
+            let (path, busy_us) = line.rsplit_once(' ').unwrap();
+            (path, busy_us.parse().unwrap())
+        })
+        .collect();
+    let paths: Vec<_> = stacks.iter().map(|(path, _)| *path).collect();
+    assert_eq!(paths, ["root", "root;child", "root;child;grandchild"]);
+    // the sleeps are in the grandchild spans, whose busy time is not counted in their ancestors
+    assert!(stacks[2].1 >= 4_000);
+    assert!(stacks[0].1 < stacks[2].1);
+}
+
+#[test]
+fn test_presets() {
+    let record = || {
+        let _span = tracing::info_span!("span_1", a = 1).entered();
+        info!(n = 1, "event 1");
+    };
+
+    let output = capture(PrettyConsoleLayer::terse(), record);
+    let event = output.lines().find(|l| l.contains("event 1")).unwrap();
+    assert!(event.contains("n=1"));
+    assert!(!output.contains("target:"));
+    assert!(!output.contains("file:"));
+    assert_eq!(output.lines().count(), 3);
+
+    let output = capture(PrettyConsoleLayer::verbose(), record);
+    let event = output.split("event 1").nth(1).unwrap();
+    assert!(event.contains("time: "));
+    assert!(event.contains("span.id: "));
+    assert!(event.contains("target: "));
+    assert!(event.contains("file: src/sub/tests.rs:"));
+    assert!(event.contains("thread: "));
+    assert!(output.contains("(busy="));
+    // the wrapped mode prints the span tree once closed, so the entry precedes the event
+    assert!(output.find("span_1").unwrap() < output.find("event 1").unwrap());
+
+    // NB: the times and durations differ, but the lines are the same
+    let labels = |output: String| -> Vec<String> {
+        output
+            .lines()
+            .map(|l| l.split([':', '(']).next().unwrap().to_string())
+            .collect()
+    };
+    assert_eq!(
+        labels(capture(PrettyConsoleLayer::new(), record)),
+        labels(capture(PrettyConsoleLayer::default(), record))
+    );
+}
+
+#[test]
+fn test_timestamp_uptime() {
+    let layer = PrettyConsoleLayer::default().timestamp_mode(TimestampMode::Uptime);
+    let output = capture(layer, || {
+        info!("event 1");
+        std::thread::sleep(Duration::from_millis(20));
+        info!("event 2");
+    });
+
+    let times: Vec<f64> = output
+        .split("time: ")
+        .skip(1)
+        .map(|s| {
+            let time = s.split_whitespace().next().unwrap();
+            assert!(time.starts_with('+'), "{time}");
+            time.trim_start_matches('+')
+                .trim_end_matches('s')
+                .parse()
+                .unwrap()
+        })
+        .collect();
+    assert_eq!(times.len(), 2);
+    assert!(times[1] >= times[0] + 0.02, "{times:?}");
+
+    // the uptime starts when the layer is built, not when the options are
+    let layer = PrettyConsoleLayer::default().timestamp_mode(TimestampMode::Uptime);
+    let options = layer.options().clone();
+    std::thread::sleep(Duration::from_millis(50));
+    let output = capture(PrettyConsoleLayer::with_options(options), || {
+        info!("event 1")
+    });
+    let time = output.split("time: +").nth(1).unwrap();
+    let time: f64 = time.split('s').next().unwrap().parse().unwrap();
+    assert!(time < 0.05, "{time}");
+
+    // hiding the time is the same as no timestamp
+    let layer = PrettyConsoleLayer::default()
+        .timestamp_mode(TimestampMode::Uptime)
+        .show_time(false);
+    let output = capture(layer, || info!("event 1"));
+    assert!(!output.contains("time: "));
+}
+
+#[test]
+fn test_show_delta() {
+    let layer = PrettyConsoleLayer::default().show_delta(true);
+    let output = capture(layer, || {
+        info!("event 1");
+        std::thread::sleep(Duration::from_millis(20));
+        info!("event 2");
+    });
+
+    let deltas: Vec<u128> = output
+        .split("delta: +")
+        .skip(1)
+        .map(|s| s.split("ms").next().unwrap().parse().unwrap())
+        .collect();
+    assert_eq!(deltas.len(), 2);
+    assert!(deltas[1] >= 20, "{deltas:?}");
+}
+
+#[test]
+fn test_indent_str() {
+    let layer = PrettyConsoleLayer::default()
+        .wrapped(true)
+        .show_time(false)
+        .indent_str("| ");
+    let output = capture(layer, || {
+        let _span_1 = tracing::info_span!("span_1").entered();
+        let _span_2 = tracing::info_span!("span_2").entered();
+        info!("event 1");
+    });
+    let line = |name: &str| {
+        output
+            .lines()
+            .find(|l| l.contains(name))
+            .unwrap()
+            .to_string()
+    };
+    assert!(!line("span_1").starts_with("| "));
+    assert!(line("span_2").starts_with("| ") && !line("span_2").starts_with("| | "));
+    assert!(line("event 1").starts_with("| | "));
+    assert!(line("span.name: span_2").starts_with("| | "));
+
+    // an empty unit falls back to the spaces
+    let layer = PrettyConsoleLayer::default()
+        .wrapped(true)
+        .show_time(false)
+        .indent_str("");
+    let output = capture(layer, || {
+        let _span_1 = tracing::info_span!("span_1").entered();
+        info!("event 1");
+    });
+    let event = output.lines().find(|l| l.contains("event 1")).unwrap();
+    assert!(event.starts_with("      "));
+}
+
+#[test]
+fn test_tree_guides() {
+    let layer = PrettyConsoleLayer::default()
+        .wrapped(true)
+        .show_time(false)
+        .tree_guides(true);
+    let output = capture(layer, || {
+        let _span_1 = tracing::info_span!("span_1").entered();
+        tracing::info_span!("child_1").in_scope(|| info!("event 1"));
+        tracing::info_span!("child_2").in_scope(|| info!("event 2"));
+    });
+
+    let line = |name: &str| {
+        output
+            .lines()
+            .find(|l| l.contains(name))
+            .unwrap()
+            .to_string()
+    };
+    assert!(line("{span_1}").starts_with('{'));
+    assert!(line("{child_1}").starts_with('├'));
+    assert!(line("{child_2}").starts_with('└'));
+    assert!(line("INFO  event 1").starts_with("│     └"));
+    assert!(line("INFO  event 2").starts_with("      └"));
+    assert!(line("!{child_1}").starts_with("│     !"));
+}
+
+#[test]
+fn test_collapse_faster_than() {
+    let layer = PrettyConsoleLayer::default()
+        .wrapped(true)
+        .show_time(false)
+        .collapse_faster_than(Duration::from_secs(1));
+    let output = capture(layer, || {
+        let _span_1 = tracing::info_span!("span_1").entered();
+        info!("event 0");
+        tracing::info_span!("empty").in_scope(|| {});
+        tracing::info_span!("quiet").in_scope(|| {
+            info!("event 1");
+            tracing::info_span!("inner").in_scope(|| info!("event 3"));
+        });
+        tracing::info_span!("failing").in_scope(|| warn!("event 2"));
+    });
+
+    assert!(output.contains("{span_1}"));
+    assert!(!output.contains("empty"));
+    assert!(!output.contains("{quiet}"));
+    assert!(!output.contains("{inner}"));
+    assert!(output.contains("{failing}"));
+
+    // the events of the collapsed spans are printed at the level of the parent events
+    let indent = |message: &str| {
+        let line = output.lines().find(|l| l.contains(message)).unwrap();
+        line.len() - line.trim_start().len()
+    };
+    assert_eq!(indent("INFO  event 1"), indent("INFO  event 0"));
+    assert_eq!(indent("INFO  event 3"), indent("INFO  event 0"));
+    assert!(indent("WARN  event 2") > indent("INFO  event 0"));
+}
+
+#[test]
+fn test_slow_threshold() {
+    let layer = PrettyConsoleLayer::default()
+        .show_time(false)
+        .slow_threshold(Duration::from_millis(10));
+    let output = capture(layer, || {
+        tracing::info_span!("slow").in_scope(|| std::thread::sleep(Duration::from_millis(20)));
+        tracing::info_span!("fast").in_scope(|| {});
+    });
+
+    let exit_line = |name: &str| {
+        output
+            .lines()
+            .rev()
+            .find(|l| l.contains(&format!("{{{name}}}")))
+            .unwrap()
+            .to_string()
+    };
+    assert!(exit_line("slow").ends_with("⚠ SLOW"));
+    assert!(!exit_line("fast").contains("SLOW"));
+}
+
+#[test]
+fn test_show_event_count() {
+    let record = || {
+        let _span_1 = tracing::info_span!("span_1").entered();
+        info!("event 1");
+        tracing::info_span!("child").in_scope(|| info!("event 2"));
+        info!("event 3");
+    };
+
+    for wrapped in [false, true] {
+        let layer = PrettyConsoleLayer::default()
+            .wrapped(wrapped)
+            .show_time(false)
+            .show_event_count(true);
+        let output = capture(layer, record);
+        let exit_line = |name: &str| {
+            output
+                .lines()
+                .rev()
+                .find(|l| l.contains(&format!("{{{name}}}")))
+                .unwrap()
+                .to_string()
+        };
+        assert!(exit_line("span_1").ends_with("(3 events)"), "{output}");
+        assert!(exit_line("child").ends_with("(1 event)"), "{output}");
+    }
+}
+
+#[test]
+fn test_target_route() {
+    let routed = TestWriter::default();
+    let layer = PrettyConsoleLayer::default().target_route("sqlx", routed.clone());
+    let output = capture(layer, || {
+        info!(target: "sqlx::query", "query");
+        info!(target: "app", "request");
+    });
+
+    assert!(routed.output().contains("query"));
+    assert!(!routed.output().contains("request"));
+    assert!(output.contains("request"));
+    assert!(!output.contains("query"));
+}
+
+/// A writer counting the flushes
+#[derive(Debug, Clone, Default)]
+struct FlushCounter(Arc<Mutex<usize>>);
+
+impl Write for FlushCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        *self.0.lock().unwrap() += 1;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_flush_policy() {
+    let counter = FlushCounter::default();
+    let layer = PrettyConsoleLayer::default().flush_policy(FlushPolicy::Batched(3));
+    let handle = layer.handle();
+    handle.set_event_writer(counter.clone());
+
+    let subscriber = tracing_subscriber::registry().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        for i in 0..7 {
+            info!(i, "event");
+        }
+    });
+    assert_eq!(*counter.0.lock().unwrap(), 2);
+
+    handle.flush();
+    assert_eq!(*counter.0.lock().unwrap(), 3);
+}
+
+#[test]
+fn test_strip_ansi() {
+    let colored = "\x1b[1;31mred\x1b[0m \x1b]8;;file:///a.rs\x1b\\a.rs\x1b]8;;\x1b\\";
+    assert_eq!(strip_ansi(colored.as_bytes()), b"red a.rs");
+}
+
+#[test]
+fn test_no_color() {
+    std::env::set_var("NO_COLOR", "1");
+    let layer = PrettyConsoleLayer::default();
+    let explicit_layer = PrettyConsoleLayer::default().with_ansi(true);
+    std::env::remove_var("NO_COLOR");
+
+    let output = capture(layer, || info!("\x1b[31mred\x1b[0m message"));
+    assert!(!output.contains("\x1b["));
+    let output = capture(explicit_layer, || info!("\x1b[31mred\x1b[0m message"));
+    assert!(output.contains("\x1b[31mred"));
+}
+
+#[test]
+fn test_line_numbers() {
+    let layer = PrettyConsoleLayer::default()
+        .show_time(false)
+        .line_numbers(true);
+    let output = capture(layer, || {
+        let _span = tracing::info_span!("span_1").entered();
+        info!("event 1");
+    });
+    let lines: Vec<&str> = output.lines().collect();
+    for (i, line) in lines.iter().enumerate() {
+        assert!(line.starts_with(&format!("{:>5} ", i + 1)));
+    }
+}
+
+#[test]
+fn test_duration_human() {
+    let cases = [
+        (Duration::from_micros(500), "500us"),
+        (Duration::from_millis(5), "5.00ms"),
+        (Duration::from_secs(2), "2.00s"),
+    ];
+    for (duration, expected) in cases {
+        assert_eq!(format_duration(duration, true), expected);
+    }
+    assert_eq!(format_duration(Duration::from_secs(2), false), "2000000us");
+
+    let layer = PrettyConsoleLayer::default().duration_human(true);
+    let output = capture(layer, || {
+        let _span = tracing::info_span!("span_1").entered();
+        std::thread::sleep(Duration::from_millis(5));
+    });
+    let exit = &output[output.find("!{span_1}").unwrap()..];
+    assert!(exit.contains("ms"));
+}
+
+#[test]
+fn test_sinks() {
+    let plain = TestWriter::default();
+    let layer = PrettyConsoleLayer::default().sink(plain.clone(), false);
+    let output = capture(layer, || {
+        info!("event");
+    });
+
+    assert!(output.contains("event"));
+    assert!(plain.output().contains("event"));
+    assert!(!plain.output().contains('\x1b'));
+}
+
+#[test]
+fn test_tsv() {
+    let layer = PrettyConsoleLayer::default().tsv(true);
+    let output = capture(layer, || {
+        let span = tracing::info_span!("span_1");
+        let _guard = span.enter();
+        info!(key = 1, "line 1\nline 2");
+        info!("event 2");
+    });
+
+    let lines = output.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0], "timestamp\tlevel\ttarget\tmessage\tspan\tfields");
+    let columns = lines[1].split('\t').collect::<Vec<_>>();
+    assert_eq!(columns.len(), 6);
+    assert_eq!(columns[3], "line 1\\nline 2");
+    assert_eq!(columns[4], "span_1");
+    assert_eq!(columns[5], "key=1");
+}
+
+#[test]
+fn test_error_summary() {
+    let layer = PrettyConsoleLayer::default().error_summary_on_exit(true);
+    let writer = TestWriter::default();
+    layer.handle().set_event_writer(writer.clone());
+    let guard = layer.summary_guard();
+
+    let subscriber = tracing_subscriber::registry().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        let span = tracing::info_span!("span_1");
+        let _guard = span.enter();
+        tracing::error!("error 1");
+        info!("event 1");
+    });
+    assert!(!writer.output().contains("ERRORS:"));
+
+    drop(guard);
+    let output = writer.output();
+    let summary = &output[output.find("ERRORS:").unwrap()..];
+    assert!(summary.contains("[span_1] error 1"));
+    assert!(!summary.contains("event 1"));
+}
+
+#[test]
+fn test_sampling_summary() {
+    let layer = PrettyConsoleLayer::default().span_sample_rate(0.5);
+    let writer = TestWriter::default();
+    layer.handle().set_event_writer(writer.clone());
+    layer.handle().set_span_writer(writer.clone());
+    let guard = layer.summary_guard();
+
+    // NB: 1 span out of 2 is sampled, starting with the 2nd one
+    let subscriber = tracing_subscriber::registry().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        for _ in 0..3 {
+            let _span = tracing::info_span!("span").entered();
+        }
+    });
+    assert_eq!(
+        writer
+            .output()
+            .matches("(1 spans skipped by sampling)")
+            .count(),
+        1
+    );
+
+    // the remaining count is printed on exit
+    drop(guard);
+    assert_eq!(
+        writer
+            .output()
+            .matches("(1 spans skipped by sampling)")
+            .count(),
+        2
+    );
+}
+
+#[test]
+fn test_target_summary() {
+    let layer = PrettyConsoleLayer::default().target_summary_on_exit(true);
+    let writer = TestWriter::default();
+    layer.handle().set_event_writer(writer.clone());
+    let guard = layer.summary_guard();
+
+    let subscriber = tracing_subscriber::registry().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        info!(target: "app::db", "query 1");
+        info!(target: "app::db", "query 2");
+        info!(target: "app::http", "request");
+    });
+
+    drop(guard);
+    let output = writer.output();
+    let summary = &output[output.find("TARGETS:").unwrap()..];
+    assert!(summary.contains("\n  app 3\n    db 2\n    http 1"));
+}
+
+#[test]
+fn test_time_epoch() {
+    let epoch = time::OffsetDateTime::now_utc() + time::Duration::hours(1);
+    let layer = PrettyConsoleLayer::default().time_epoch(epoch);
+    let output = capture(layer, || {
+        info!("event");
+    });
+    assert!(output.contains("time: -3599."));
+}
+
+#[test]
+fn test_use_local_time() {
+    let layer = PrettyConsoleLayer::default().use_local_time(true);
+    assert!(layer.legend_text().contains("time: timestamp (local time)"));
+    let layer = PrettyConsoleLayer::default();
+    assert!(layer.legend_text().contains("time: timestamp (UTC)"));
+}
+
+#[test]
+fn test_event_timestamps() {
+    let layer = PrettyConsoleLayer::default().wrapped(true);
+    let output = capture(layer, || {
+        let _span = tracing::info_span!("span_1").entered();
+        info!("event 1");
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        info!("event 2");
+    });
+
+    let times: Vec<&str> = output
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("time: "))
+        .collect();
+    // span entry, event 1, event 2
+    assert_eq!(times.len(), 3);
+    assert_ne!(times[1], times[2]);
+}
+
+#[test]
+fn test_dynamic_span_event_level() {
+    let layer = PrettyConsoleLayer::default().dynamic_span_event_level(|attrs| {
+        match attrs.get("verbose").as_deref() {
+            Some("true") => tracing::Level::TRACE,
+            _ => tracing::Level::WARN,
+        }
+    });
+    let output = capture(layer, || {
+        info!("root event");
+        {
+            let span = tracing::info_span!("span_1", verbose = true);
+            let _guard = span.enter();
+            info!("verbose event");
+        }
+        let span = tracing::info_span!("span_2");
+        let _guard = span.enter();
+        info!("quiet event");
+        tracing::warn!("warn event");
+    });
+
+    assert!(output.contains("root event"));
+    assert!(output.contains("verbose event"));
+    assert!(!output.contains("quiet event"));
+    assert!(output.contains("warn event"));
+}
+
+#[test]
+fn test_lazy_attrs() {
+    use crate::sub::Lazy;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    let body = || {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+        "body".to_string()
+    };
+
+    let output = capture(PrettyConsoleLayer::default(), || {
+        let _span = tracing::info_span!("span_1", lazy.body = ?Lazy(body)).entered();
+    });
+    assert!(output.contains("body=body"));
+    assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+
+    let layer = PrettyConsoleLayer::default().events_only(true);
+    capture(layer, || {
+        let _span = tracing::info_span!("span_1", lazy.body = ?Lazy(body)).entered();
+    });
+    assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn test_events_from_other_layer() {
+    let writer = TestWriter::default();
+    let layer = PrettyConsoleLayer::default().events_from_other_layer(true);
+    let handle = layer.handle();
+    handle.set_event_writer(writer.clone());
+    handle.set_span_writer(writer.clone());
+    let fmt_writer = writer.clone();
+    let fmt_layer = tracing_subscriber::fmt::layer()
+        .with_ansi(false)
+        .with_writer(move || fmt_writer.clone());
+
+    let subscriber = tracing_subscriber::registry().with(fmt_layer).with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        let span = tracing::info_span!("span_1");
+        let _guard = span.enter();
+        info!("event 1");
+    });
+
+    let output = writer.output();
+    assert_eq!(output.matches("event 1").count(), 1);
+    assert!(output.contains("{span_1}"));
+    assert!(output.contains("!{span_1}"));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_yaml() {
+    let layer = PrettyConsoleLayer::default().yaml(true);
+    let output = capture(layer, || {
+        let span = tracing::info_span!("span_1", key = "a: b");
+        let _guard = span.enter();
+        let child = tracing::info_span!("span_2");
+        let _child_guard = child.enter();
+        info!("event: 1");
+    });
+
+    let doc: serde_yaml::Value = serde_yaml::from_str(&output).unwrap();
+    let span_1 = &doc["span_1"];
+    assert_eq!(span_1["attrs"]["key"].as_str(), Some("a: b"));
+    let span_2 = &span_1["children"][0]["span_2"];
+    assert_eq!(span_2["events"][0]["message"].as_str(), Some("event: 1"));
+}
+
+#[test]
+fn test_dedupe_trees() {
+    let layer = PrettyConsoleLayer::default()
+        .wrapped(true)
+        .dedupe_trees(true);
+    let output = capture(layer, || {
+        for i in 0..3 {
+            let _span = tracing::info_span!("request", i).entered();
+            info!("handled");
+        }
+        let _span = tracing::info_span!("shutdown").entered();
+    });
+
+    assert_eq!(output.matches("handled").count(), 1);
+    assert!(output.contains("(x2) {request}"));
+    assert!(output.contains("{shutdown}"));
+}
+
+#[test]
+fn test_on_record() {
+    let layer = PrettyConsoleLayer::default().wrapped(true);
+    let output = capture(layer, || {
+        let span = tracing::info_span!("span_1", user = tracing::field::Empty, n = 1);
+        let _guard = span.enter();
+        span.record("user", "alice");
+        span.record("n", 2);
+    });
+
+    let entry = &output[..output.find("!{span_1}").unwrap()];
+    assert!(entry.contains("user=alice"));
+    assert!(entry.contains("n=2"));
+    assert!(!entry.contains("n=1"));
+}
+
+#[test]
+fn test_attr_placement() {
+    let layer = PrettyConsoleLayer::default()
+        .show_time(false)
+        .attr_placement(AttrPlacement::Exit);
+    let output = capture(layer, || {
+        let span = tracing::info_span!("span_1", status = tracing::field::Empty);
+        let _guard = span.enter();
+        span.record("status", "done");
+    });
+
+    let exit = &output[output.find("!{span_1}").unwrap()..];
+    assert!(exit.contains("status=done"));
+    assert!(!output[..output.find("!{span_1}").unwrap()].contains("status="));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_record_replay() {
+    let path = std::env::temp_dir().join(format!("tracing-ext-{}.jsonl", std::process::id()));
+    let layer = PrettyConsoleLayer::default().record_to(&path).unwrap();
+    let live = capture(layer.wrapped(true).show_time(false), || {
+        info!("root event");
+        let span = tracing::info_span!("span_1", key = 1);
+        let _guard = span.enter();
+        let child = tracing::info_span!("span_2");
+        let _child_guard = child.enter();
+        warn!(value = 2, "event 1");
+    });
+
+    let writer = TestWriter::default();
+    PrettyConsoleLayer::default()
+        .wrapped(true)
+        .show_time(false)
+        .replay(&path, writer.clone())
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let strip_durations = |s: &str| {
+        s.lines()
+            .map(|l| l.split(" (id=").next().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(strip_durations(&writer.output()), strip_durations(&live));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_replay_timestamps() {
+    let path = std::env::temp_dir().join(format!("tracing-ext-time-{}.jsonl", std::process::id()));
+    let layer = PrettyConsoleLayer::default().record_to(&path).unwrap();
+    let live = capture(layer.wrapped(true), || {
+        let _span = tracing::info_span!("span_1").entered();
+        info!("event 1");
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        info!("event 2");
+    });
+    std::thread::sleep(std::time::Duration::from_millis(10));
+
+    let writer = TestWriter::default();
+    PrettyConsoleLayer::default()
+        .wrapped(true)
+        .replay(&path, writer.clone())
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let times = |s: &str| {
+        s.lines()
+            .filter_map(|line| line.trim().strip_prefix("time: ").map(str::to_string))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(times(&live).len(), 3);
+    assert_eq!(times(&writer.output()), times(&live));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_replay_matches_live() {
+    let layer = || {
+        PrettyConsoleLayer::default()
+            .wrapped(true)
+            .timestamp_mode(TimestampMode::Uptime)
+            .show_ancestor_ids(true)
+    };
+    let path = std::env::temp_dir().join(format!("tracing-ext-live-{}.jsonl", std::process::id()));
+    let live = capture(layer().record_to(&path).unwrap(), || {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        let _root = tracing::info_span!("root").entered();
+        let _unentered = tracing::info_span!("unentered");
+        let _child = tracing::info_span!("child").entered();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        info!("event 1");
+    });
+
+    let writer = TestWriter::default();
+    layer().replay(&path, writer.clone()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    // NB: the durations are captured in microseconds
+    let mask_durations = |s: &str| {
+        s.lines()
+            .map(|l| l.split(" (id=").next().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+    assert!(live.contains("(never entered)"));
+    assert!(live.contains("parents: ["));
+    assert_eq!(mask_durations(&writer.output()), mask_durations(&live));
+}
+
+#[test]
+fn test_warn_late_events() {
+    let layer = PrettyConsoleLayer::default().warn_late_events(true);
+    let output = capture(layer, || {
+        let span = tracing::info_span!("span_1");
+        span.in_scope(|| info!("active event"));
+        // NB: the span is exited, but not closed yet
+        info!(parent: &span, "late event");
+    });
+    assert!(output.contains("(span not active) late event"));
+    assert!(!output.contains("(span not active) active event"));
+}
+
+#[test]
+fn test_focus() {
+    let layer = PrettyConsoleLayer::default()
+        .wrapped(true)
+        .focus(|message, _| message.contains("timeout"));
+    let output = capture(layer, || {
+        let _root = tracing::info_span!("root").entered();
+        info!("root event");
+        {
+            let _span = tracing::info_span!("ok_span").entered();
+            info!("ok");
+        }
+        {
+            let _span = tracing::info_span!("failing_span").entered();
+            warn!("timeout");
+        }
+    });
+    assert!(output.contains("{root}"));
+    assert!(output.contains("root event"));
+    assert!(output.contains("{failing_span}"));
+    assert!(!output.contains("ok_span"));
+}
+
+#[test]
+fn test_unentered_spans() {
+    for wrapped in [false, true] {
+        let layer = PrettyConsoleLayer::default().wrapped(wrapped);
+        let output = capture(layer, || {
+            let _span = tracing::info_span!("unentered");
+            let _entered = tracing::info_span!("entered").entered();
+        });
+        assert!(output.contains("{unentered} (never entered)"));
+        assert!(!output.contains("{entered} (never entered)"));
+
+        let layer = PrettyConsoleLayer::default()
+            .wrapped(wrapped)
+            .hide_unentered_spans(true);
+        let output = capture(layer, || {
+            let _span = tracing::info_span!("unentered");
+            let _entered = tracing::info_span!("entered").entered();
+        });
+        assert!(!output.contains("unentered"));
+        assert!(output.contains("{entered}"));
+    }
+}
+
+#[test]
+fn test_hide_unentered_parent() {
+    let layer = PrettyConsoleLayer::default()
+        .wrapped(true)
+        .show_time(false)
+        .hide_unentered_spans(true);
+    let output = capture(layer, || {
+        let _root = tracing::info_span!("root").entered();
+        info!("event 0");
+        let unentered = tracing::info_span!("unentered");
+        info!(parent: &unentered, "event 1");
+        let _child = tracing::info_span!(parent: &unentered, "child").entered();
+    });
+    assert!(!output.contains("{unentered}"));
+    let indent = |needle: &str| {
+        let line = output.lines().find(|l| l.contains(needle)).unwrap();
+        line.len() - line.trim_start().len()
+    };
+    // the items of the hidden span are moved to the root
+    assert_eq!(indent("event 1"), indent("event 0"));
+    assert_eq!(indent("{child}"), indent("event 0"));
+}
+
+#[test]
+fn test_zero_span_budget() {
+    let layer = PrettyConsoleLayer::default()
+        .wrapped(true)
+        .span_budget(|_| Some(Duration::ZERO));
+    let output = capture(layer, || {
+        let _span = tracing::info_span!("span_1").entered();
+    });
+    let exit = output.lines().find(|l| l.contains("!{span_1}")).unwrap();
+    assert!(exit.contains("/0us"), "{exit}");
+    assert!(!exit.contains('%'), "{exit}");
+}
+
+#[test]
+fn test_collapse_empty_spans() {
+    let layer = PrettyConsoleLayer::default()
+        .wrapped(true)
+        .collapse_empty_spans(true);
+    let output = capture(layer, || {
+        let _root = tracing::info_span!("root").entered();
+        let _empty = tracing::info_span!("empty", a = 1).entered();
+    });
+    assert!(output.contains("{empty}..!{empty} "));
+    assert!(output.contains("a=1"));
+    assert!(output.contains("{root}\n"));
+    assert!(output.contains("!{root} "));
+}
+
+#[test]
+fn test_make_writer() {
+    let writer = TestWriter::default();
+    let make_writer = writer.clone();
+    let layer = PrettyConsoleLayer::default()
+        .show_time(false)
+        .with_writer(move || make_writer.clone());
+    let subscriber = tracing_subscriber::registry().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        let _span = tracing::info_span!("span_1").entered();
+        info!("hello");
+    });
+
+    let output = writer.output();
+    assert!(output.contains("{span_1}"));
+    assert!(output.contains("hello"));
+    assert!(output.contains("!{span_1}"));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_json() {
+    let layer = PrettyConsoleLayer::default().json(true);
+    let output = capture(layer, || {
+        let _span = tracing::info_span!("span_1", user = "alice").entered();
+        info!(n = 42, key = "a b", "event 1");
+    });
+
+    let line: serde_json::Value = serde_json::from_str(output.trim_end()).unwrap();
+    assert_eq!(line["level"], "INFO");
+    assert_eq!(line["message"], "event 1");
+    assert_eq!(line["span"]["name"], "span_1");
+    assert_eq!(line["span"]["fields"]["user"], "alice");
+    assert_eq!(line["fields"]["n"], 42);
+    assert_eq!(line["fields"]["key"], "a b");
+    assert!(line["timestamp"].is_string());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_chrome_trace() {
+    let layer = PrettyConsoleLayer::default()
+        .chrome_trace(true)
+        .wrapped(true);
+    let output = capture(layer, || {
+        let _root = tracing::info_span!("root", user = "alice").entered();
+        {
+            let _child = tracing::info_span!("child").entered();
+            info!(n = 1, "event 1");
+        }
+        let _child = tracing::info_span!("child").entered();
+    });
+
+    let mut lines = output.lines();
+    assert_eq!(lines.next(), Some("["));
+    let events: Vec<serde_json::Value> = lines
+        .map(|line| serde_json::from_str(line.strip_suffix(',').unwrap()).unwrap())
+        .collect();
+    let phases: Vec<_> = events.iter().map(|e| e["ph"].as_str().unwrap()).collect();
+    assert_eq!(phases, ["B", "B", "i", "E", "B", "E", "E"]);
+
+    // each end closes the last open span, and the timestamps never decrease
+    let mut open = vec![];
+    let mut last_ts = 0.0;
+    for event in &events {
+        let ts = event["ts"].as_f64().unwrap();
+        assert!(ts >= last_ts);
+        last_ts = ts;
+        assert_eq!(event["pid"], std::process::id());
+        assert_eq!(event["tid"], events[0]["tid"]);
+        match event["ph"].as_str().unwrap() {
+            "B" => open.push(event["name"].clone()),
+            "E" => assert_eq!(open.pop(), Some(event["name"].clone())),
+            _ => {}
+        }
+    }
+    assert!(open.is_empty());
+    assert_eq!(events[0]["args"]["user"], "alice");
+    assert_eq!(events[2]["name"], "event 1");
+    assert_eq!(events[2]["args"]["n"], 1);
+}
+
+#[test]
+fn test_logfmt() {
+    let layer = PrettyConsoleLayer::default().logfmt(true);
+    let output = capture(layer, || {
+        info!(path = "/a b", quote = r#"say "hi""#, n = 1, "event 1");
+    });
+    assert!(output.starts_with("ts="));
+    assert!(output.contains(" level=info msg=\"event 1\" "));
+    assert!(output.contains(" path=\"/a b\""));
+    assert!(output.contains(r#" quote="say \"hi\"""#));
+    assert!(output.contains(" n=1"));
+}
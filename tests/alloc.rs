@@ -0,0 +1,77 @@
+//! Allocations per record (streaming mode)
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use tracing::info;
+use tracing_ext::sub::PrettyConsoleLayer;
+use tracing_subscriber::prelude::*;
+
+/// An allocator counting the allocations
+struct CountingAlloc;
+
+/// Number of allocations
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// Number of records of each measure
+const RECORDS: usize = 1000;
+
+/// Returns the number of allocations per event (after a warm up)
+fn allocations_per_event(layer: PrettyConsoleLayer) -> usize {
+    let layer = layer.show_time(false).with_writer(std::io::sink);
+    let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(layer));
+
+    let span = tracing::info_span!("span", a = 1);
+    let _span = span.enter();
+
+    // warm up (lazy statics, buffers)
+    for i in 0..10 {
+        info!(i, "event");
+    }
+
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for i in 0..RECORDS {
+        info!(i, name = "value", "event");
+    }
+    (ALLOCATIONS.load(Ordering::Relaxed) - before) / RECORDS
+}
+
+/// Allocations per event before the serialization buffer was reused (and the lines were written
+/// with the intermediate colored strings)
+///
+/// NB: about 40 allocations per event with the reused buffer, mostly the recorded fields
+const ALLOCATIONS_BASELINE: usize = 62;
+
+#[test]
+fn test_allocations_per_record() {
+    // NB: the record buffer is reused, and the lines are written without colored strings (colors
+    // are disabled when the output is not a terminal)
+    let per_event = allocations_per_event(PrettyConsoleLayer::default());
+    assert!(
+        per_event < ALLOCATIONS_BASELINE,
+        "{per_event} allocations per event (baseline: {ALLOCATIONS_BASELINE})"
+    );
+
+    // without ANSI, the lines are not stripped (no escape codes are written)
+    let per_event = allocations_per_event(PrettyConsoleLayer::default().with_ansi(false));
+    assert!(
+        per_event < ALLOCATIONS_BASELINE,
+        "{per_event} allocations per event without ANSI (baseline: {ALLOCATIONS_BASELINE})"
+    );
+}
@@ -0,0 +1,70 @@
+//! ANSI escape codes
+//!
+//! NB: the colors are forced, since the tests do not run in a terminal (in their own process, as
+//! the override is global).
+
+use std::sync::{Arc, Mutex};
+
+use tracing::{error, info};
+use tracing_ext::sub::PrettyConsoleLayer;
+use tracing_subscriber::prelude::*;
+
+/// A writer appending to a shared buffer
+struct WriterRef(Arc<Mutex<Vec<u8>>>);
+
+impl std::io::Write for WriterRef {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns the output of a layer (including the summaries)
+fn capture(layer: PrettyConsoleLayer) -> String {
+    colored::control::set_override(true);
+
+    let output = Arc::new(Mutex::new(Vec::<u8>::new()));
+    let writer = output.clone();
+    let layer = layer
+        .error_summary_on_exit(true)
+        .target_summary_on_exit(true)
+        .with_writer(move || WriterRef(writer.clone()));
+    let guard = layer.summary_guard();
+    let subscriber = tracing_subscriber::registry().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        let _span = tracing::info_span!("span_1").entered();
+        info!("\x1b[31mred\x1b[0m message");
+        error!("failure");
+    });
+    drop(guard);
+
+    let output = output.lock().unwrap().clone();
+    String::from_utf8(output).unwrap()
+}
+
+#[test]
+fn test_with_ansi() {
+    let output = capture(PrettyConsoleLayer::default().with_ansi(true));
+    assert!(output.contains("\x1b["));
+
+    let output = capture(PrettyConsoleLayer::default().with_ansi(false));
+    assert!(output.contains("red message"));
+    assert!(output.contains("ERRORS:"));
+    assert!(output.contains("TARGETS:"));
+    assert!(!output.contains("\x1b["), "{output:?}");
+}
+
+#[test]
+fn test_legend_with_ansi() {
+    colored::control::set_override(true);
+
+    // NB: the legend is not written through the layer writers (no escape codes stripped)
+    let legend = PrettyConsoleLayer::default().with_ansi(true).legend_text();
+    assert!(legend.contains("\x1b["));
+
+    let legend = PrettyConsoleLayer::default().with_ansi(false).legend_text();
+    assert!(!legend.contains("\x1b["), "{legend:?}");
+}
@@ -0,0 +1,84 @@
+//! Writes to the underlying writer, with the flush policies
+//!
+//! A buffered writer only writes to the underlying writer (eg a file) when its buffer is full, or
+//! when it is flushed.
+
+use std::{
+    io::{BufWriter, Write},
+    sync::{Arc, Mutex},
+};
+
+use tracing::info;
+use tracing_ext::sub::{FlushPolicy, PrettyConsoleLayer};
+use tracing_subscriber::prelude::*;
+
+/// Number of events of each measure
+const EVENTS: usize = 1000;
+
+/// Capacity of the buffered writer
+const CAPACITY: usize = 8 * 1024;
+
+/// Writes, bytes and flushes of a writer
+#[derive(Debug, Clone, Copy, Default)]
+struct Counts {
+    writes: usize,
+    bytes: usize,
+    flushes: usize,
+}
+
+/// A writer counting the writes and flushes
+#[derive(Clone, Default)]
+struct CountingWriter(Arc<Mutex<Counts>>);
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut counts = self.0.lock().unwrap();
+        counts.writes += 1;
+        counts.bytes += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.lock().unwrap().flushes += 1;
+        Ok(())
+    }
+}
+
+/// Returns the counts of the writer underlying a buffered writer
+fn write_events(policy: FlushPolicy) -> Counts {
+    let counter = CountingWriter::default();
+    let layer = PrettyConsoleLayer::default()
+        .show_time(false)
+        .flush_policy(policy);
+    let handle = layer.handle();
+    handle.set_event_writer(BufWriter::with_capacity(CAPACITY, counter.clone()));
+    let subscriber = tracing_subscriber::registry().with(layer);
+
+    tracing::subscriber::with_default(subscriber, || {
+        for i in 0..EVENTS {
+            info!(i, "event");
+        }
+    });
+    handle.flush();
+
+    let counts = *counter.0.lock().unwrap();
+    counts
+}
+
+#[test]
+fn test_flush_writes() {
+    // each line is written and flushed (+ the final flush)
+    let counts = write_events(FlushPolicy::PerLine);
+    assert_eq!(counts.writes, EVENTS);
+    assert_eq!(counts.flushes, EVENTS + 1);
+
+    // the lines are written when a batch is flushed, or when the buffer is full
+    for n in [16, 64, 256] {
+        let counts = write_events(FlushPolicy::Batched(n));
+        assert_eq!(counts.flushes, EVENTS / n + 1);
+        assert!(
+            counts.writes <= counts.flushes + counts.bytes / CAPACITY,
+            "Batched({n}): {counts:?}"
+        );
+    }
+}
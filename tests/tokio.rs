@@ -75,3 +75,78 @@ async fn test_tokio() {
     let (_, _) = tokio::join!(handle_1, handle_2);
     info!("Test OK");
 }
+
+#[tracing::instrument]
+async fn wait_then_work() {
+    sleep(std::time::Duration::from_millis(50)).await;
+    std::thread::sleep(std::time::Duration::from_millis(5));
+}
+
+#[tokio::test]
+async fn test_busy_idle() {
+    use std::sync::{Arc, Mutex};
+
+    let output = Arc::new(Mutex::new(Vec::<u8>::new()));
+    let writer = output.clone();
+    let layer = PrettyConsoleLayer::default()
+        .show_busy_idle(true)
+        .duration_human(false)
+        .with_writer(move || WriterRef(writer.clone()));
+    let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(layer));
+
+    wait_then_work().await;
+
+    // an exit line is printed on each poll, the last one ends with `<total>us (busy=<busy>us idle=<idle>us)`
+    let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+    let micros = |s: &str| s.trim_end_matches("us").parse::<u64>().unwrap();
+    let (total, busy_idle) = output
+        .lines()
+        .rev()
+        .find_map(|line| line.split_once(" (busy="))
+        .expect("busy time not printed");
+    let total = micros(total.rsplit(' ').next().unwrap());
+    let (busy, idle) = busy_idle
+        .trim_end_matches(')')
+        .split_once(" idle=")
+        .unwrap();
+    let (busy, idle) = (micros(busy), micros(idle));
+    assert!(busy >= 5_000);
+    assert!(busy < total);
+    assert!(idle >= 50_000);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn test_task_id() {
+    use std::sync::{Arc, Mutex};
+
+    let output = Arc::new(Mutex::new(Vec::<u8>::new()));
+    let writer = output.clone();
+    let layer = PrettyConsoleLayer::default()
+        .show_task_id(true)
+        .with_writer(move || WriterRef(writer.clone()));
+    let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(layer));
+
+    let task_id = tokio::spawn(async {
+        info!("inside task");
+        tokio::task::id()
+    })
+    .await
+    .unwrap();
+
+    let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+    assert!(output.contains(&format!("task.id: {task_id}")));
+}
+
+/// A writer appending to a shared buffer
+struct WriterRef(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for WriterRef {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}